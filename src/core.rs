@@ -1,5 +1,16 @@
+pub mod address;
+pub mod amount;
 pub mod block;
 pub mod block_chain;
 pub mod cyphers;
+pub mod hd;
+pub mod keystore;
+pub mod mem_pool;
+pub mod merkle;
+pub mod mnemonic;
+pub mod psbt;
+pub mod script;
+pub mod storage;
 pub mod transaction;
+pub mod utxo;
 pub mod wallet;
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+// Base58Check addresses
+// - base58(version ++ hash160 ++ checksum)
+//   checksum = first 4 bytes of sha256(sha256(version ++ hash160))
+// - the version byte tags what the hash commits to: a public key (P2PKH, "1...")
+//   or a redeem script (P2SH, "3...")
+// - a typo changes the checksum: parsing rejects it instead of paying to nowhere
+// - serialized as its string, so decoding a message with a malformed address fails
+
+pub const PUBKEY_HASH_VERSION: u8 = 0x00;
+pub const SCRIPT_HASH_VERSION: u8 = 0x05;
+
+const CHECKSUM_SIZE: usize = 4;
+const ADDRESS_SIZE: usize = 1 + 20 + CHECKSUM_SIZE;
+
+#[derive(Debug, PartialEq)]
+pub enum AddressErr {
+    InvalidBase58,
+    InvalidLength(usize), // decoded bytes
+    InvalidChecksum,
+    UnknownVersion(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Address {
+    PubKeyHash([u8; 20]),
+    ScriptHash([u8; 20]),
+}
+
+impl fmt::Display for AddressErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid address: {:?}", self)
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = Sha256::digest(Sha256::digest(payload));
+    hash[..CHECKSUM_SIZE].try_into().unwrap()
+}
+
+impl Address {
+    pub fn version(&self) -> u8 {
+        match self {
+            Address::PubKeyHash(_) => PUBKEY_HASH_VERSION,
+            Address::ScriptHash(_) => SCRIPT_HASH_VERSION,
+        }
+    }
+
+    pub fn hash(&self) -> &[u8; 20] {
+        match self {
+            Address::PubKeyHash(hash) | Address::ScriptHash(hash) => hash,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = vec![self.version()];
+        bytes.extend(self.hash());
+        bytes.extend(checksum(&bytes));
+        write!(f, "{}", bs58::encode(bytes).into_string())
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressErr;
+
+    fn from_str(addr: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(addr)
+            .into_vec()
+            .map_err(|_| AddressErr::InvalidBase58)?;
+        if bytes.len() != ADDRESS_SIZE {
+            return Err(AddressErr::InvalidLength(bytes.len()));
+        }
+        let (payload, sum) = bytes.split_at(ADDRESS_SIZE - CHECKSUM_SIZE);
+        if checksum(payload) != sum {
+            return Err(AddressErr::InvalidChecksum);
+        }
+
+        let hash: [u8; 20] = payload[1..].try_into().unwrap();
+        match payload[0] {
+            PUBKEY_HASH_VERSION => Ok(Address::PubKeyHash(hash)),
+            SCRIPT_HASH_VERSION => Ok(Address::ScriptHash(hash)),
+            version => Err(AddressErr::UnknownVersion(version)),
+        }
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = AddressErr;
+
+    fn try_from(addr: String) -> Result<Self, Self::Error> {
+        addr.parse()
+    }
+}
+
+impl From<Address> for String {
+    fn from(addr: Address) -> Self {
+        addr.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_and_parse() {
+        // the key hash of the genesis block coinbase, and a known P2SH address
+        let genesis: [u8; 20] = hex::decode("62e907b15cbf27d5425399ebf6f0fb50ebb88f18")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let addr = Address::PubKeyHash(genesis);
+        assert_eq!(addr.to_string(), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+        assert_eq!("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".parse(), Ok(addr));
+
+        let script_hash: [u8; 20] = hex::decode("74f209f6ea907e2ea48f74fae05782ae8a665257")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let addr = Address::ScriptHash(script_hash);
+        assert_eq!(addr.to_string(), "3CMNFxN1oHBc4R1EpboAL5yzHGgE611Xou");
+        assert_eq!(addr.to_string().parse(), Ok(addr));
+
+        // serialized as the string, checked again when decoded
+        let encoded = bincode::serialize(&addr).unwrap();
+        assert_eq!(encoded, bincode::serialize(&addr.to_string()).unwrap());
+        assert_eq!(bincode::deserialize::<Address>(&encoded).unwrap(), addr);
+        let typo = bincode::serialize("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb").unwrap();
+        assert!(bincode::deserialize::<Address>(&typo).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        // one character off
+        assert_eq!(
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb".parse::<Address>(),
+            Err(AddressErr::InvalidChecksum)
+        );
+        // 0, O, I and l aren't base58
+        assert_eq!(
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfN0".parse::<Address>(),
+            Err(AddressErr::InvalidBase58)
+        );
+        assert_eq!(
+            "1A1zP1eP5QGefi2DMPTfTL".parse::<Address>(),
+            Err(AddressErr::InvalidLength(17))
+        );
+        let mut bytes = vec![0x6f];
+        bytes.extend([0u8; 20]);
+        bytes.extend(checksum(&bytes));
+        assert_eq!(
+            bs58::encode(bytes).into_string().parse::<Address>(),
+            Err(AddressErr::UnknownVersion(0x6f))
+        );
+    }
+}
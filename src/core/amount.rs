@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Money as an integer number of base units
+// - 1 coin = COIN base units, whole-coin notation has at most COIN_DECIMALS decimals
+// - arithmetic is checked: None on overflow/underflow instead of wrapping
+// - never negative, a change that would be is an error of the caller
+
+pub const COIN_DECIMALS: u32 = 8;
+pub const COIN: u64 = 10u64.pow(COIN_DECIMALS);
+
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Amount(u64);
+
+#[derive(Debug, PartialEq)]
+pub enum AmountParseErr {
+    Empty,
+    InvalidDigit,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub const fn from_units(units: u64) -> Self {
+        Self(units)
+    }
+
+    pub const fn from_coins(coins: u64) -> Option<Self> {
+        match coins.checked_mul(COIN) {
+            Some(units) => Some(Self(units)),
+            None => None,
+        }
+    }
+
+    pub const fn units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Self)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Self)
+    }
+
+    pub fn checked_mul(self, n: u64) -> Option<Amount> {
+        self.0.checked_mul(n).map(Self)
+    }
+
+    pub fn checked_div(self, n: u64) -> Option<Amount> {
+        self.0.checked_div(n).map(Self)
+    }
+
+    // None as soon as the running total overflows, whatever the order of `amounts`
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+// "1", "0.5", "21000000.00000001"
+impl FromStr for Amount {
+    type Err = AmountParseErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (coins, decimals) = s.split_once('.').unwrap_or((s, ""));
+        if coins.is_empty() || (s.contains('.') && decimals.is_empty()) {
+            return Err(AmountParseErr::Empty);
+        }
+        let all_digits = |part: &str| part.bytes().all(|c| c.is_ascii_digit());
+        if !all_digits(coins) || !all_digits(decimals) {
+            return Err(AmountParseErr::InvalidDigit);
+        }
+        if decimals.len() > COIN_DECIMALS as usize {
+            return Err(AmountParseErr::TooManyDecimals);
+        }
+
+        let coins: u64 = coins.parse().map_err(|_| AmountParseErr::Overflow)?;
+        let fraction = match decimals.is_empty() {
+            true => 0,
+            false => {
+                let padding = 10u64.pow(COIN_DECIMALS - decimals.len() as u32);
+                decimals.parse::<u64>().unwrap() * padding
+            }
+        };
+        Amount::from_coins(coins)
+            .and_then(|amount| amount.checked_add(Amount(fraction)))
+            .ok_or(AmountParseErr::Overflow)
+    }
+}
+
+// whole coins, trailing zero decimals are left out
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let coins = self.0 / COIN;
+        let fraction = self.0 % COIN;
+        if fraction == 0 {
+            return write!(f, "{}", coins);
+        }
+        let decimals = format!("{:0width$}", fraction, width = COIN_DECIMALS as usize);
+        write!(f, "{}.{}", coins, decimals.trim_end_matches('0'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        for s in ["0", "1", "0.5", "0.00000001", "21000000", "1.10000001"] {
+            let amount: Amount = s.parse().unwrap();
+            assert_eq!(amount.to_string(), s);
+        }
+        assert_eq!("1.5".parse(), Ok(Amount::from_units(150_000_000)));
+        assert_eq!("1.50".parse::<Amount>().unwrap().to_string(), "1.5");
+
+        assert_eq!("".parse::<Amount>(), Err(AmountParseErr::Empty));
+        assert_eq!(".5".parse::<Amount>(), Err(AmountParseErr::Empty));
+        assert_eq!("1.".parse::<Amount>(), Err(AmountParseErr::Empty));
+        assert_eq!("-1".parse::<Amount>(), Err(AmountParseErr::InvalidDigit));
+        assert_eq!("1e3".parse::<Amount>(), Err(AmountParseErr::InvalidDigit));
+        assert_eq!(
+            "0.000000001".parse::<Amount>(),
+            Err(AmountParseErr::TooManyDecimals)
+        );
+        assert_eq!(
+            "184467440738".parse::<Amount>(),
+            Err(AmountParseErr::Overflow)
+        );
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let one = Amount::from_coins(1).unwrap();
+        let tenth: Amount = "0.1".parse().unwrap();
+        assert_eq!(
+            Amount::checked_sum([tenth, tenth, tenth]),
+            "0.3".parse().ok()
+        );
+        assert_eq!(one.checked_sub(tenth), "0.9".parse().ok());
+        assert_eq!(tenth.checked_sub(one), None);
+        assert_eq!(Amount::MAX.checked_add(Amount::from_units(1)), None);
+        assert_eq!(Amount::checked_sum([Amount::MAX, one, Amount::MAX]), None);
+        assert_eq!(one.checked_div(0), None);
+        assert_eq!(Amount::from_coins(u64::MAX), None);
+    }
+}
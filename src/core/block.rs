@@ -1,21 +1,31 @@
 use super::cyphers::{Decoder, Encoder};
+use super::merkle::{transactions_merkle_root, transactions_witness_root, MerkleProof};
 use super::transaction::Transaction;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+pub const BLOCK_VERSION: u32 = 2;
+pub const ZERO_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// version(4) prev_hash(32) merkle_root(32) witness_root(32) time_stamp(8) difficulty(4) nonce(8)
+pub const HEADER_SIZE: usize = 120;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockHeader {
+    pub version: u32,
     pub time_stamp: i64,
 
-    #[serde(skip_serializing)]
     hash: String,
 
     prev_hash: String,
+    pub merkle_root: String,
+    pub witness_root: String, // the whole trxs: a block's hash names a single body
     height: u64,
+    pub difficulty: u32, // required leading zero bits of the block hash
     pub nonce: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
@@ -35,13 +45,39 @@ impl Decoder for Block {
     }
 }
 
+fn hash_bytes(hash: &str) -> Result<[u8; 32], String> {
+    hex::decode(hash)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| format!("hash should be 32 bytes: {}", hash))
+}
+
+impl BlockHeader {
+    // fixed layout, little endian integers, hashes as raw bytes
+    pub fn encode_for_hash(&self) -> Result<[u8; HEADER_SIZE], String> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[4..36].copy_from_slice(&hash_bytes(&self.prev_hash)?);
+        bytes[36..68].copy_from_slice(&hash_bytes(&self.merkle_root)?);
+        bytes[68..100].copy_from_slice(&hash_bytes(&self.witness_root)?);
+        bytes[100..108].copy_from_slice(&self.time_stamp.to_le_bytes());
+        bytes[108..112].copy_from_slice(&self.difficulty.to_le_bytes());
+        bytes[112..120].copy_from_slice(&self.nonce.to_le_bytes());
+        Ok(bytes)
+    }
+}
+
 impl Block {
     pub fn new(prev_hash: String, nonce: u64, height: u64, transactions: Vec<Transaction>) -> Self {
         let header = BlockHeader {
+            version: BLOCK_VERSION,
             hash: String::new(),
             prev_hash,
+            merkle_root: transactions_merkle_root(&transactions),
+            witness_root: transactions_witness_root(&transactions),
             time_stamp: Utc::now().timestamp(),
             height,
+            difficulty: 0,
             nonce,
         };
         Block {
@@ -50,15 +86,87 @@ impl Block {
         }
     }
 
-    pub fn gen_hash(&mut self) -> Result<String, serde_json::Error> {
-        let hash = serde_json::to_string(&self).map(sha256::digest)?;
+    // block identity and proof-of-work only cover the header,
+    // transactions are committed through `merkle_root` and `witness_root`.
+    pub fn gen_hash(&mut self) -> Result<String, String> {
+        let hash = self.compute_hash()?;
         self.header.hash = hash.clone();
-        Ok(hash.clone())
+        Ok(hash)
+    }
+
+    // commit the transactions again, after they were edited
+    pub fn commit_transactions(&mut self) {
+        self.header.merkle_root = transactions_merkle_root(&self.transactions);
+        self.header.witness_root = transactions_witness_root(&self.transactions);
+    }
+
+    pub fn compute_hash(&self) -> Result<String, String> {
+        self.header
+            .encode_for_hash()
+            .map(|bytes| sha256::digest(&bytes))
     }
 
     pub fn hash(&self) -> String {
         self.header.hash.clone()
     }
+
+    pub fn prev_hash(&self) -> &str {
+        &self.header.prev_hash
+    }
+
+    pub fn height(&self) -> u64 {
+        self.header.height
+    }
+
+    // encoded bytes
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap_or(u64::MAX) as usize
+    }
+
+    // expected number of hashes to find this block
+    pub fn work(&self) -> u128 {
+        1u128
+            .checked_shl(self.header.difficulty)
+            .unwrap_or(u128::MAX)
+    }
+
+    pub fn meets_difficulty(&self) -> bool {
+        leading_zero_bits(&self.header.hash) >= self.header.difficulty
+    }
+
+    // proof that the transaction `trx_id` is committed by `header.merkle_root`
+    pub fn merkle_proof(&self, trx_id: &str) -> Option<MerkleProof> {
+        let tx_ids: Vec<&str> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.trx_id.as_str())
+            .collect();
+        let index = tx_ids.iter().position(|id| *id == trx_id)?;
+        MerkleProof::build(&tx_ids, index)
+    }
+}
+
+// leading zero bits of a hex encoded hash
+pub fn leading_zero_bits(hash: &str) -> u32 {
+    let mut bits = 0;
+    for c in hash.chars() {
+        match c.to_digit(16) {
+            Some(0) => bits += 4,
+            Some(d) => return bits + (d as u8).leading_zeros() - 4,
+            None => return bits,
+        }
+    }
+    bits
+}
+
+#[test]
+fn test_leading_zero_bits() {
+    assert_eq!(leading_zero_bits("ffff"), 0);
+    assert_eq!(leading_zero_bits("7fff"), 1);
+    assert_eq!(leading_zero_bits("0fff"), 4);
+    assert_eq!(leading_zero_bits("001f"), 11);
+    assert_eq!(leading_zero_bits("0000"), 16);
+    assert_eq!(leading_zero_bits(""), 0);
 }
 
 #[test]
@@ -73,3 +181,45 @@ fn test_encode_block() {
     });
     assert!(true);
 }
+
+#[test]
+fn test_header_hash() {
+    let tx = |trx_id: &str| Transaction {
+        trx_id: trx_id.into(),
+        data: vec![],
+    };
+    let mut b = Block::new(ZERO_HASH.into(), 7, 1, vec![tx("trx_0")]);
+    let hash = b.gen_hash().unwrap();
+    assert_eq!(hash.len(), 64);
+
+    // transactions only count through the merkle roots: the ids, and the whole trxs
+    b.transactions[0].trx_id = "trx_1".into();
+    assert_eq!(b.gen_hash().unwrap(), hash);
+    b.header.merkle_root = transactions_merkle_root(&b.transactions);
+    let renamed = b.gen_hash().unwrap();
+    assert_ne!(renamed, hash);
+    b.transactions[0].data = vec![1, 2, 3];
+    assert_eq!(b.gen_hash().unwrap(), renamed);
+    b.commit_transactions();
+    assert_ne!(b.gen_hash().unwrap(), renamed);
+
+    let mut bad_prev = Block::new("prev_hash".into(), 0, 1, vec![]);
+    assert!(bad_prev.gen_hash().is_err());
+}
+
+#[test]
+fn test_merkle_proof() {
+    let transactions: Vec<Transaction> = (0..3)
+        .map(|i| Transaction {
+            trx_id: format!("trx_{}", i),
+            data: vec![],
+        })
+        .collect();
+    let b = Block::new(String::from("prev_hash"), 0, 1, transactions.clone());
+
+    for tx in &transactions {
+        let proof = b.merkle_proof(&tx.trx_id).unwrap();
+        assert!(proof.verify(tx, &b.header.merkle_root));
+    }
+    assert!(b.merkle_proof("unknown").is_none());
+}
@@ -1,45 +1,472 @@
-use super::block::Block;
-use super::transaction::{Transaction, TransactionData};
+use super::address::{Address, AddressErr};
+use super::amount::{Amount, COIN};
+use super::block::{Block, ZERO_HASH};
+use super::mem_pool::MemPool;
+use super::merkle::{transactions_merkle_root, transactions_witness_root};
+use super::script::Script;
+use super::storage::{BlockStore, StorageErr};
+use super::transaction::{OutPoint, Transaction, TransactionData, TxBuilder, TxValidationErr};
+use super::utxo::{BlockUndo, UtxoEntry, UtxoSet};
+use chrono::Utc;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-const DIFFICULTY: u8 = 3;
-const MINNING_SENDER: &'static str = "blockchain";
-const MINNING_REWARD: f64 = 1.0;
+const MIN_DIFFICULTY: u32 = 1;
+const MAX_DIFFICULTY: u32 = 255;
+const MAX_RETARGET_STEP: i64 = 2; // at most 4x harder or easier per retarget
+const MEDIAN_TIME_SPAN: usize = 11; // blocks
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60; // seconds
+const MINNING_REWARD: Amount = Amount::from_units(COIN);
+const MAX_HALVINGS: u64 = 64;
+const MEM_POOL_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub target_block_time: i64, // seconds
+    pub retarget_interval: u64, // blocks
+    pub initial_difficulty: u32,
+    pub initial_subsidy: Amount,
+    pub halving_interval: u64, // blocks, 0 never halves
+    pub max_supply: Amount,
+    pub max_block_size: usize,    // encoded bytes
+    pub max_mem_pool_size: usize, // encoded bytes of pending trxs kept by this node
+    pub genesis_time: i64,        // fixed: nodes of the same chain share its genesis block
+    pub genesis_nonce: u64,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self {
+            target_block_time: 10,
+            retarget_interval: 10,
+            initial_difficulty: 12, // 3 leading hex zeros
+            initial_subsidy: MINNING_REWARD,
+            halving_interval: 210_000,
+            max_supply: Amount::from_units(420_000 * COIN),
+            max_block_size: 1_000_000,
+            max_mem_pool_size: 64_000_000,
+            genesis_time: 1_735_689_600, // 2025-01-01T00:00:00Z
+            genesis_nonce: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BlockValidationErr {
+    Duplicate,
+    KnownInvalid, // the block, or one of its ancestors, was rejected before
+    UnknownParent,
+    InvalidHash,
+    InvalidPrevHash,
+    InvalidHeight { expected: u64, got: u64 },
+    InvalidDifficulty { expected: u32, got: u32 },
+    InvalidProof,
+    TimeTooOld,
+    TimeTooNew,
+    InvalidMerkleRoot,
+    InvalidWitnessRoot,
+    DuplicateTransaction { index: usize }, // a trx included twice
+    BlockTooLarge { max: usize, got: usize },
+    MissingCoinbase,
+    UnexpectedCoinbase { index: usize },
+    InvalidCoinbaseHeight,
+    CoinbaseValueTooHigh { max: Amount, got: Amount },
+    ValueOverflow,
+    InvalidTransaction { index: usize, err: TxValidationErr },
+    Storage(String),
+    NoMinerAddress, // nothing to pay the block reward to, see set_block_chain_address
+}
+
+impl BlockValidationErr {
+    // the block named by its hash is invalid whatever the time or the order blocks arrive in,
+    // it's never accepted. a hash or trxs not matching the header say nothing of the block
+    // the header commits to, a peer may send the right one.
+    fn is_permanent(&self) -> bool {
+        !matches!(
+            self,
+            BlockValidationErr::Duplicate
+                | BlockValidationErr::KnownInvalid
+                | BlockValidationErr::UnknownParent
+                | BlockValidationErr::InvalidHash
+                | BlockValidationErr::TimeTooNew
+                | BlockValidationErr::InvalidMerkleRoot
+                | BlockValidationErr::InvalidWitnessRoot
+                | BlockValidationErr::DuplicateTransaction { .. }
+                | BlockValidationErr::Storage(_)
+                | BlockValidationErr::NoMinerAddress
+        )
+    }
+}
 
 #[derive(Debug)]
 pub struct BlockChain {
-    pub mem_pool: Vec<Transaction>, // pending trxs
-    pub chain: Vec<Block>,          // should be ref with lifetime specified
-    pub block_chain_address: Option<String>,
+    pub mem_pool: MemPool,                   // pending trxs
+    pub chain: Vec<Block>,                   // active chain: genesis -> heaviest tip
+    pub side_blocks: HashMap<String, Block>, // known blocks off the active chain, by hash
+    chain_work: HashMap<String, u128>,       // cumulative work from genesis, by block hash
+    utxo: UtxoSet,                           // unspent outputs of the active chain
+    undo: HashMap<String, BlockUndo>,        // by hash of each connected block
+    invalid: HashSet<String>,                // hashes of rejected blocks, never stored
+    block_chain_address: Option<Address>,    // paid by the blocks this node mines
+    pub params: ChainParams,
+    store: Option<BlockStore>, // in-memory only when None
+    mem_pool_changed: bool,    // since it was last saved
+    mem_pool_saved_at: Instant,
 }
 
 impl BlockChain {
     pub fn new() -> Self {
-        // create genesis block
-        let mut b = Block::new("hash_0".into(), 0, 1, vec![]);
+        Self::with_params(ChainParams::default())
+    }
+
+    pub fn with_params(params: ChainParams) -> Self {
+        // create genesis block, the same for every node using `params`
+        let mut b = Block::new(ZERO_HASH.into(), params.genesis_nonce, 0, vec![]);
+        b.header.time_stamp = params.genesis_time;
+        b.header.difficulty = params.initial_difficulty;
         b.gen_hash();
 
+        Self::from_genesis(b, params)
+    }
+
+    fn from_genesis(genesis: Block, params: ChainParams) -> Self {
         BlockChain {
-            chain: vec![b],
-            mem_pool: vec![],
+            chain_work: HashMap::from([(genesis.hash(), genesis.work())]),
+            chain: vec![genesis],
+            side_blocks: HashMap::new(),
+            utxo: UtxoSet::default(),
+            undo: HashMap::new(),
+            invalid: HashSet::new(),
+            mem_pool: MemPool::new(params.max_mem_pool_size),
             block_chain_address: None,
+            params,
+            store: None,
+            mem_pool_changed: false,
+            mem_pool_saved_at: Instant::now(),
         }
     }
 
-    pub fn create_block(&mut self, prev_hash: String, nonce: u64) -> Option<&Block> {
-        let mut b = Block::new(
-            prev_hash,
-            nonce,
-            self.chain.len() as u64,
-            self.mem_pool.to_vec(),
-        );
-        b.gen_hash();
+    // load the chain persisted in `dir`, or start a new one there. the stored genesis must
+    // be the one of `params`.
+    // stored blocks are replayed through `add_block` so side branches and the utxo set
+    // are restored as well. a side block found invalid once stored (when a reorg
+    // connects it) is rejected again and skipped.
+    pub fn open<P: AsRef<Path>>(dir: P, params: ChainParams) -> Result<Self, StorageErr> {
+        let (mut store, stored) = BlockStore::open(dir)?;
+        let mut stored = stored.into_iter();
+
+        let mut bc = match stored.next() {
+            Some(genesis) => {
+                let expected = Self::with_params(params.clone()).chain[0].hash();
+                if genesis.hash() != expected {
+                    return Err(StorageErr::GenesisMismatch {
+                        stored: genesis.hash(),
+                        expected,
+                    });
+                }
+                Self::from_genesis(genesis, params)
+            }
+            None => {
+                let bc = Self::with_params(params);
+                store.append_block(&bc.chain[0])?;
+                bc
+            }
+        };
+        for b in stored {
+            if let Err(e) = bc.add_block(b) {
+                eprintln!("action=replay_block status=skip err={:?}", e);
+            }
+        }
+
+        for tx in store.load_mem_pool()? {
+            if let Err(e) = bc.add_transaction(tx) {
+                eprintln!("action=reload_mem_pool status=drop err={:?}", e);
+            }
+        }
+        bc.store = Some(store);
+        bc.mem_pool_changed = false; // as stored, but what was dropped
+        Ok(bc)
+    }
+
+    // append the accepted block `hash` (the active tip or a side block) to the log.
+    // if that fails it's forgotten and the active chain goes back to `prev_tip`:
+    // a block missing from the log can't have stored descendants.
+    fn store_block(&mut self, hash: &str, prev_tip: &str) -> Result<(), BlockValidationErr> {
+        let Some(store) = self.store.as_mut() else {
+            return Ok(());
+        };
+        let b = match self.side_blocks.get(hash) {
+            Some(b) => b,
+            None => self.chain.last().unwrap(),
+        };
+        let Err(e) = store.append_block(b) else {
+            return Ok(());
+        };
+
+        if self.latest_block().unwrap().hash() == hash {
+            self.disconnect_block();
+        }
+        self.side_blocks.remove(hash);
+        self.chain_work.remove(hash);
+        if self.latest_block().unwrap().hash() != prev_tip {
+            // connected before, so it connects again
+            self.reorganize(prev_tip).unwrap();
+        }
+        self.revalidate_mem_pool();
+        Err(BlockValidationErr::Storage(format!("{:?}", e)))
+    }
+
+    // address the blocks mined by this node pay their reward to, nothing is mined without one
+    pub fn set_block_chain_address(&mut self, addr: &str) -> Result<(), AddressErr> {
+        self.block_chain_address = Some(addr.parse()?);
+        Ok(())
+    }
+
+    // the whole mem_pool is rewritten on each save: at most every MEM_POOL_SAVE_INTERVAL,
+    // and when the chain is dropped
+    fn persist_mem_pool(&mut self) {
+        self.mem_pool_changed = true;
+        if self.mem_pool_saved_at.elapsed() >= MEM_POOL_SAVE_INTERVAL {
+            self.save_mem_pool();
+        }
+    }
+
+    // write the pending trxs changed since the last save
+    pub fn save_mem_pool(&mut self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        if !self.mem_pool_changed {
+            return;
+        }
+        if let Err(e) = store.save_mem_pool(&self.mem_pool.transactions()) {
+            eprintln!("action=persist_mem_pool status=fail err={:?}", e);
+            return;
+        }
+        self.mem_pool_changed = false;
+        self.mem_pool_saved_at = Instant::now();
+    }
 
+    // mine a block on the known block `prev_hash`, trying nonces from `nonce` on, and add it
+    // as any block: validated before it's appended. on the tip it takes pending trxs.
+    pub fn create_block(
+        &mut self,
+        prev_hash: String,
+        nonce: u64,
+    ) -> Result<&Block, BlockValidationErr> {
+        if self.block_chain_address.is_none() {
+            return Err(BlockValidationErr::NoMinerAddress);
+        }
+        let tip_hash = self.latest_block().unwrap().hash();
+        let transactions = if prev_hash == tip_hash {
+            self.block_template(self.chain.len() as u64)
+        } else {
+            vec![]
+        };
+        let b = self
+            .mine_from(&prev_hash, nonce, transactions)
+            .ok_or(BlockValidationErr::UnknownParent)?;
+        let hash = b.hash();
+        self.add_block(b)?;
+        Ok(self.block_by_hash(&hash).unwrap())
+    }
+
+    // append to the active chain, `b` has to extend the current tip
+    // and only spend outputs unspent on the active chain.
+    fn connect_block(&mut self, b: Block) -> Result<(), BlockValidationErr> {
+        let (undo, fees) = self
+            .utxo
+            .apply_block(&b)
+            .map_err(|(index, err)| BlockValidationErr::InvalidTransaction { index, err })?;
+        if let Err(e) = self.validate_coinbase_value(&b, fees) {
+            self.utxo.undo_block(undo);
+            return Err(e);
+        }
+        self.undo.insert(b.hash(), undo);
+
+        let prev_work = self.chain_work.get(b.prev_hash()).copied().unwrap_or(0);
+        self.chain_work
+            .insert(b.hash(), prev_work.saturating_add(b.work()));
+
+        // confirmed trxs leave the mem_pool
+        self.mem_pool.remove_included(&b.transactions);
         self.chain.push(b);
+        Ok(())
+    }
 
-        // empty the mem_pool
-        self.mem_pool = vec![];
+    // pop the active tip, its trxs are pending again
+    // (conflicts with pending trxs are left to revalidate_mem_pool)
+    fn disconnect_block(&mut self) -> Option<Block> {
+        if self.chain.len() <= 1 {
+            return None; // never disconnect genesis
+        }
 
-        self.chain.last()
+        let b = self.chain.pop()?;
+        if let Some(undo) = self.undo.remove(&b.hash()) {
+            self.utxo.undo_block(undo);
+        }
+        for tx in b.transactions.iter().skip(1) {
+            if self.mem_pool.contains(tx) {
+                continue;
+            }
+            let inserted = self
+                .check_pending(tx, &HashSet::new())
+                .and_then(|fee| self.mem_pool.insert(tx.clone(), fee));
+            if let Err(e) = inserted {
+                eprintln!("action=disconnect_block status=drop_tx err={:?}", e);
+            }
+        }
+        Some(b)
+    }
+
+    fn active_height(&self, hash: &str) -> Option<usize> {
+        self.chain.iter().rposition(|b| b.hash() == hash)
+    }
+
+    pub fn block_by_hash(&self, hash: &str) -> Option<&Block> {
+        match self.active_height(hash) {
+            Some(height) => self.chain.get(height),
+            None => self.side_blocks.get(hash),
+        }
+    }
+
+    // known blocks from genesis up to `tip_hash`
+    fn branch_to(&self, tip_hash: &str) -> Option<Vec<&Block>> {
+        let mut side_branch = vec![];
+        let mut hash = tip_hash;
+        loop {
+            // once on the active chain the rest of the branch is shared with it
+            if let Some(height) = self.active_height(hash) {
+                let mut branch: Vec<&Block> = self.chain[..=height].iter().collect();
+                branch.extend(side_branch.into_iter().rev());
+                return Some(branch);
+            }
+
+            let b = self.side_blocks.get(hash)?;
+            side_branch.push(b);
+            hash = b.prev_hash();
+        }
+    }
+
+    // accept a block whose parent is any known block.
+    // the active chain follows the tip with the most cumulative work,
+    // on a tie the first seen tip is kept.
+    // a block is stored once connected, or validated as a side block. an invalid one
+    // is remembered so it's rejected right away when it's sent again.
+    pub fn add_block(&mut self, b: Block) -> Result<(), BlockValidationErr> {
+        let hash = b.hash();
+        if self.chain_work.contains_key(&hash) {
+            return Err(BlockValidationErr::Duplicate);
+        }
+        if self.invalid.contains(&hash) || self.invalid.contains(b.prev_hash()) {
+            return Err(BlockValidationErr::KnownInvalid);
+        }
+
+        let branch = self
+            .branch_to(b.prev_hash())
+            .ok_or(BlockValidationErr::UnknownParent)?;
+        if let Err(e) = self.validate_block(&b, &branch) {
+            if e.is_permanent() {
+                self.invalid.insert(hash);
+            }
+            return Err(e);
+        }
+
+        let prev_tip = self.latest_block().unwrap().hash();
+        if prev_tip == b.prev_hash() {
+            if let Err(e) = self.connect_block(b) {
+                if e.is_permanent() {
+                    self.invalid.insert(hash);
+                }
+                return Err(e);
+            }
+            self.revalidate_mem_pool();
+            return self.store_block(&hash, &prev_tip);
+        }
+
+        let prev_work = self.chain_work.get(b.prev_hash()).copied().unwrap_or(0);
+        let work = prev_work.saturating_add(b.work());
+        self.chain_work.insert(hash.clone(), work);
+        self.side_blocks.insert(hash.clone(), b);
+
+        if work > self.tip_work() {
+            let reorganized = self.reorganize(&hash);
+            self.revalidate_mem_pool();
+            reorganized?;
+        }
+        self.store_block(&hash, &prev_tip)
+    }
+
+    fn tip_work(&self) -> u128 {
+        let tip_hash = self.latest_block().unwrap().hash();
+        self.chain_work.get(&tip_hash).copied().unwrap_or(0)
+    }
+
+    // switch the active chain to end at `new_tip` (a side block).
+    // when a block of the new branch turns out to spend what it can't,
+    // it's dropped with its descendants and the previous active chain is restored.
+    fn reorganize(&mut self, new_tip: &str) -> Result<(), BlockValidationErr> {
+        let mut connecting = vec![];
+        let mut hash = new_tip.to_string();
+        while let Some(b) = self.side_blocks.remove(&hash) {
+            hash = b.prev_hash().to_string();
+            connecting.push(b);
+        }
+
+        // `hash` is now the fork point on the active chain
+        let fork_hash = hash;
+        let mut disconnected = vec![];
+        while self.latest_block().unwrap().hash() != fork_hash {
+            match self.disconnect_block() {
+                Some(b) => {
+                    disconnected.push(b.hash());
+                    self.side_blocks.insert(b.hash(), b);
+                }
+                None => break,
+            }
+        }
+
+        while let Some(b) = connecting.pop() {
+            let hash = b.hash();
+            if let Err(e) = self.connect_block(b) {
+                // `b` is dropped, so is every known block built on it
+                for descendant in connecting.drain(..) {
+                    self.side_blocks.insert(descendant.hash(), descendant);
+                }
+                self.drop_descendants(&hash);
+
+                while self.latest_block().unwrap().hash() != fork_hash {
+                    let b = self.disconnect_block().unwrap();
+                    self.side_blocks.insert(b.hash(), b);
+                }
+                for hash in disconnected.iter().rev() {
+                    // connected before, so it connects again
+                    let b = self.side_blocks.remove(hash).unwrap();
+                    self.connect_block(b).unwrap();
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // forget the invalid block `hash` and the side blocks built on it: they can't be part of
+    // any valid branch, so they're no candidates for the active chain anymore
+    fn drop_descendants(&mut self, hash: &str) {
+        let mut dropped = vec![hash.to_string()];
+        while let Some(parent) = dropped.pop() {
+            self.side_blocks.remove(&parent);
+            self.chain_work.remove(&parent);
+            self.invalid.insert(parent.clone());
+            dropped.extend(
+                self.side_blocks
+                    .values()
+                    .filter(|b| b.prev_hash() == parent)
+                    .map(|b| b.hash()),
+            );
+        }
     }
 
     pub fn latest_block(&self) -> Option<&Block> {
@@ -51,9 +478,109 @@ impl BlockChain {
         self.chain.iter_mut().nth(nth)
     }
 
-    pub fn add_transaction(&mut self, tx: Transaction) -> Option<&Transaction> {
-        self.mem_pool.push(tx);
-        self.mem_pool.last()
+    // pending trxs may only spend confirmed outputs, nothing already spent by another pending trx
+    // unless it replaces that one (see MemPool::replace).
+    // accept a signed trx spending outputs its sender owns, that fits in the next block.
+    pub fn add_transaction(&mut self, tx: Transaction) -> Result<&Transaction, TxValidationErr> {
+        tx.verify()?;
+        let replaced: HashSet<String> = self
+            .mem_pool
+            .replaced_by(&tx)?
+            .into_iter()
+            .map(|entry| entry.tx.trx_id.clone())
+            .collect();
+        // inputs of `tx` a pending trx it doesn't replace spends
+        let spent: HashSet<OutPoint> = tx
+            .tx_data()?
+            .inputs
+            .into_iter()
+            .map(|input| input.prev_out)
+            .filter(|prev_out| {
+                self.mem_pool
+                    .spender(prev_out)
+                    .is_some_and(|trx_id| !replaced.contains(trx_id))
+            })
+            .collect();
+        let fee = self.check_pending(&tx, &spent)?;
+
+        let trx_id = tx.trx_id.clone();
+        let (replaced, evicted) = self.mem_pool.replace(tx, fee)?;
+        for replaced in replaced {
+            eprintln!(
+                "action=add_transaction status=replace trx_id={}",
+                replaced.trx_id
+            );
+        }
+        for evicted in evicted {
+            eprintln!(
+                "action=add_transaction status=evict trx_id={}",
+                evicted.trx_id
+            );
+        }
+        self.persist_mem_pool();
+        Ok(&self
+            .mem_pool
+            .iter()
+            .find(|entry| entry.tx.trx_id == trx_id)
+            .unwrap()
+            .tx)
+    }
+
+    // `tx` as part of the next block on the active chain, returns its fee
+    fn check_pending(
+        &self,
+        tx: &Transaction,
+        spent: &HashSet<OutPoint>,
+    ) -> Result<Amount, TxValidationErr> {
+        let height = self.chain.len() as u64;
+        if !tx
+            .tx_data()?
+            .is_final(height, Self::median_time_past(&self.chain))
+        {
+            return Err(TxValidationErr::NotFinal);
+        }
+        self.utxo.check_tx(tx, height, spent)
+    }
+
+    // pending trxs by fee rate that fit in the block at `height` next to its coinbase
+    fn block_template(&self, height: u64) -> Vec<Transaction> {
+        let Some(coinbase) = self.with_coinbase(height, vec![]) else {
+            return vec![]; // nothing is mined
+        };
+        let mut b = Block::new(ZERO_HASH.into(), 0, height, coinbase);
+        b.gen_hash();
+        let max_size = self.params.max_block_size.saturating_sub(b.size());
+        self.mem_pool.block_template(max_size)
+    }
+
+    // drop pending trxs the active chain no longer allows, after its tip moved.
+    // higher fee rates win conflicts between pending trxs.
+    fn revalidate_mem_pool(&mut self) {
+        let mut spent = HashSet::new();
+        for entry in self.mem_pool.take() {
+            if let Ok(fee) = self.check_pending(&entry.tx, &spent) {
+                let inputs = entry.tx.tx_data().unwrap().inputs;
+                spent.extend(inputs.into_iter().map(|input| input.prev_out));
+                let _ = self.mem_pool.insert(entry.tx, fee);
+            }
+        }
+        self.persist_mem_pool();
+    }
+
+    pub fn unspent_of(&self, addr: &str) -> Vec<(OutPoint, UtxoEntry)> {
+        self.utxo.unspent_of(addr)
+    }
+
+    // unspent outputs of `addr` no pending trx spends yet
+    pub fn spendable_of(&self, addr: &str) -> Vec<(OutPoint, UtxoEntry)> {
+        self.unspent_of(addr)
+            .into_iter()
+            .filter(|(outpoint, _)| !self.mem_pool.is_spent(outpoint))
+            .collect()
+    }
+
+    pub fn balance_of(&self, addr: &str) -> Amount {
+        self.utxo.balance_of(addr)
     }
 
     pub fn txs_of_addr(&self, addr: String) -> Vec<Transaction> {
@@ -90,7 +617,11 @@ impl BlockChain {
                 }
 
                 let txdata = txdata_de_result.unwrap();
-                if txdata.sender_addr.eq(&addr) || txdata.receiver_addr.eq(&addr) {
+                let received = txdata.outputs.iter().any(|output| output.pays_to(&addr));
+                let spent = (0..txdata.inputs.len())
+                    .filter_map(|index| txdata.signer_address(index))
+                    .any(|signer| signer.eq(&addr));
+                if received || spent {
                     addr_txs.push(tx.clone());
                 }
             }
@@ -99,50 +630,332 @@ impl BlockChain {
         addr_txs
     }
 
+    // whether `addr` was ever paid, by a block or a pending trx (see Wallet::discover)
+    pub fn is_used(&self, addr: &str) -> bool {
+        let pays = |tx: &Transaction| {
+            tx.tx_data()
+                .is_ok_and(|data| data.outputs.iter().any(|output| output.pays_to(addr)))
+        };
+        self.chain.iter().flat_map(|b| &b.transactions).any(pays)
+            || self.mem_pool.transactions().iter().any(pays)
+    }
+
+    // difficulty the block at `height` has to meet.
+    // every `retarget_interval` blocks the difficulty is moved by log2(expected / actual)
+    // time spent on the last interval, bounded by MAX_RETARGET_STEP.
+    pub fn required_difficulty(&self, height: usize) -> u32 {
+        self.next_difficulty(&self.chain[..height])
+    }
+
+    // difficulty of the block extending `branch` (ordered from genesis)
+    fn next_difficulty<B: Borrow<Block>>(&self, branch: &[B]) -> u32 {
+        let height = branch.len();
+        if height == 0 {
+            return self.params.initial_difficulty;
+        }
+
+        let prev = branch[height - 1].borrow();
+        let interval = self.params.retarget_interval as usize;
+        if interval < 2 || height < interval || !height.is_multiple_of(interval) {
+            return prev.header.difficulty;
+        }
+
+        let first = branch[height - interval].borrow();
+        let actual = prev.header.time_stamp - first.header.time_stamp;
+        let expected = self.params.target_block_time * (interval as i64 - 1);
+
+        let mut step = 0;
+        if actual <= 0 {
+            step = MAX_RETARGET_STEP;
+        } else if actual < expected {
+            while step < MAX_RETARGET_STEP && actual << (step + 1) <= expected {
+                step += 1;
+            }
+        } else {
+            while step > -MAX_RETARGET_STEP && actual >= expected << (1 - step) {
+                step -= 1;
+            }
+        }
+
+        (prev.header.difficulty as i64 + step).clamp(MIN_DIFFICULTY as i64, MAX_DIFFICULTY as i64)
+            as u32
+    }
+
     pub fn valid_proof(&self, adding_block: &mut Block) -> bool {
-        match adding_block
-            .gen_hash()
-            .map(|hash| hash.chars().take(DIFFICULTY as usize).all(|c| c == '0'))
+        adding_block.header.difficulty == self.required_difficulty(self.chain.len())
+            && adding_block.gen_hash().is_ok()
+            && adding_block.meets_difficulty()
+    }
+
+    // blocks can't be older than the median time of their last MEDIAN_TIME_SPAN ancestors
+    fn median_time_past<B: Borrow<Block>>(branch: &[B]) -> i64 {
+        let mut times: Vec<i64> = branch
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|b| b.borrow().header.time_stamp)
+            .collect();
+        times.sort();
+        times.get(times.len() / 2).copied().unwrap_or(i64::MIN)
+    }
+
+    // consensus rules for `b` extending `branch` (ordered from genesis),
+    // returns the first rule `b` breaks.
+    pub fn validate_block<B: Borrow<Block>>(
+        &self,
+        b: &Block,
+        branch: &[B],
+    ) -> Result<(), BlockValidationErr> {
+        let hash = b
+            .compute_hash()
+            .map_err(|_| BlockValidationErr::InvalidHash)?;
+        if hash != b.hash() {
+            return Err(BlockValidationErr::InvalidHash);
+        }
+
+        let prev = branch
+            .last()
+            .ok_or(BlockValidationErr::UnknownParent)?
+            .borrow();
+        if b.prev_hash() != prev.hash() {
+            return Err(BlockValidationErr::InvalidPrevHash);
+        }
+        if b.height() != prev.height() + 1 {
+            return Err(BlockValidationErr::InvalidHeight {
+                expected: prev.height() + 1,
+                got: b.height(),
+            });
+        }
+
+        let difficulty = self.next_difficulty(branch);
+        if b.header.difficulty != difficulty {
+            return Err(BlockValidationErr::InvalidDifficulty {
+                expected: difficulty,
+                got: b.header.difficulty,
+            });
+        }
+        if !b.meets_difficulty() {
+            return Err(BlockValidationErr::InvalidProof);
+        }
+
+        if b.header.time_stamp < Self::median_time_past(branch) {
+            return Err(BlockValidationErr::TimeTooOld);
+        }
+        if b.header.time_stamp > Utc::now().timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockValidationErr::TimeTooNew);
+        }
+
+        if b.header.merkle_root != transactions_merkle_root(&b.transactions) {
+            return Err(BlockValidationErr::InvalidMerkleRoot);
+        }
+        if b.header.witness_root != transactions_witness_root(&b.transactions) {
+            return Err(BlockValidationErr::InvalidWitnessRoot);
+        }
+        let mut tx_ids = HashSet::new();
+        if let Some(index) = b
+            .transactions
+            .iter()
+            .position(|tx| !tx_ids.insert(&tx.trx_id))
         {
-            Ok(valid) => valid,
-            Err(_) => false,
+            return Err(BlockValidationErr::DuplicateTransaction { index });
+        }
+        if b.size() > self.params.max_block_size {
+            return Err(BlockValidationErr::BlockTooLarge {
+                max: self.params.max_block_size,
+                got: b.size(),
+            });
+        }
+        self.validate_coinbase(b)?;
+        let median_time_past = Self::median_time_past(branch);
+        for (index, tx) in b.transactions.iter().enumerate().skip(1) {
+            if tx.is_coinbase() {
+                return Err(BlockValidationErr::UnexpectedCoinbase { index });
+            }
+            tx.verify()
+                .map_err(|err| BlockValidationErr::InvalidTransaction { index, err })?;
+            if !tx.tx_data().unwrap().is_final(b.height(), median_time_past) {
+                return Err(BlockValidationErr::InvalidTransaction {
+                    index,
+                    err: TxValidationErr::NotFinal,
+                });
+            }
         }
+
+        Ok(())
     }
 
-    pub fn proof_of_work(&self) -> u64 {
+    // the whole active chain, genesis is trusted as long as its hash is intact
+    pub fn validate(&self) -> Result<(), BlockValidationErr> {
+        let genesis = &self.chain[0];
+        if genesis.compute_hash().ok() != Some(genesis.hash()) {
+            return Err(BlockValidationErr::InvalidHash);
+        }
+
+        // spends are checked against a utxo set rebuilt from genesis
+        let mut utxo = UtxoSet::default();
+        for height in 1..self.chain.len() {
+            let b = &self.chain[height];
+            self.validate_block(b, &self.chain[..height])?;
+            let (_, fees) = utxo
+                .apply_block(b)
+                .map_err(|(index, err)| BlockValidationErr::InvalidTransaction { index, err })?;
+            self.validate_coinbase_value(b, fees)?;
+        }
+        Ok(())
+    }
+
+    // None without a miner address
+    pub fn proof_of_work(&self) -> Option<Block> {
         // challenge(future nonce) + prev_hash + transactions(pool)
-        let mut nonce = 0;
-        loop {
-            let prev_hash = self.latest_block().unwrap().hash();
-            let transactions = self.mem_pool.to_vec();
-            let block_height = self.chain.len();
-            let mut adding_block = Block::new(prev_hash, nonce, block_height as u64, transactions);
-            if self.valid_proof(&mut adding_block) {
-                break;
+        let prev_hash = self.latest_block().unwrap().hash();
+        let height = self.chain.len() as u64;
+        self.mine_on(&prev_hash, self.block_template(height))
+    }
+
+    // mine a block extending the known block `prev_hash`, None without a miner address
+    fn mine_on(&self, prev_hash: &str, transactions: Vec<Transaction>) -> Option<Block> {
+        self.mine_from(prev_hash, 0, transactions)
+    }
+
+    fn mine_from(
+        &self,
+        prev_hash: &str,
+        nonce: u64,
+        transactions: Vec<Transaction>,
+    ) -> Option<Block> {
+        let branch = self.branch_to(prev_hash)?;
+        let height = branch.len() as u64;
+        let transactions = self.with_coinbase(height, transactions)?;
+        let mut b = Block::new(prev_hash.into(), nonce, height, transactions);
+        b.header.difficulty = self.next_difficulty(&branch);
+        while !(b.gen_hash().is_ok() && b.meets_difficulty()) {
+            b.header.nonce += 1;
+        }
+        Some(b)
+    }
+
+    // reward of the block at `height`: halves every `halving_interval` blocks
+    // and is cut down so that no more than `max_supply` is ever issued.
+    pub fn block_subsidy(&self, height: u64) -> Amount {
+        let interval = self.params.halving_interval;
+        let scheduled = |era: u64| {
+            if era >= MAX_HALVINGS {
+                return Amount::ZERO;
             }
-            nonce += 1;
+            Amount::from_units(self.params.initial_subsidy.units() >> era)
+        };
+        let era_of = |h: u64| h.checked_div(interval).unwrap_or(0);
+        if height == 0 {
+            return Amount::ZERO; // genesis
+        }
+
+        // issued by blocks 1..height, era by era, saturating: all that matters is what's left
+        let mut issued = Amount::ZERO;
+        let mut from = 1;
+        while from < height {
+            let era = era_of(from);
+            let era_end = if interval == 0 {
+                height
+            } else {
+                ((era + 1) * interval).min(height)
+            };
+            issued = scheduled(era)
+                .checked_mul(era_end - from)
+                .and_then(|era_issued| issued.checked_add(era_issued))
+                .unwrap_or(Amount::MAX);
+            from = era_end;
         }
-        nonce
+
+        let remaining = self
+            .params
+            .max_supply
+            .checked_sub(issued)
+            .unwrap_or(Amount::ZERO);
+        scheduled(era_of(height)).min(remaining)
     }
 
-    pub fn minning(&mut self) {
-        // self.add_transaction(
-        //     MINNING_SENDER.into(),
-        //     self.block_chain_address.as_ref().unwrap().clone(),
-        //     MINNING_REWARD,
-        // );
+    // fees of `transactions` that could extend the active chain,
+    // others count for nothing
+    fn block_fees(&self, transactions: &[Transaction]) -> Option<Amount> {
+        Amount::checked_sum(
+            transactions
+                .iter()
+                .filter_map(|tx| self.check_pending(tx, &HashSet::new()).ok()),
+        )
+    }
 
-        let prev_hash = self.latest_block().unwrap().hash();
-        let nonce = self.proof_of_work();
-        self.create_block(prev_hash, nonce);
+    // coinbase paying `block_chain_address` the subsidy plus the fees of `transactions`.
+    // None without a miner address: the reward is never burnt.
+    fn with_coinbase(
+        &self,
+        height: u64,
+        transactions: Vec<Transaction>,
+    ) -> Option<Vec<Transaction>> {
+        let miner = self.block_chain_address.as_ref()?;
+        let subsidy = self.block_subsidy(height);
+        let value = self
+            .block_fees(&transactions)
+            .and_then(|fees| subsidy.checked_add(fees))
+            .unwrap_or(subsidy);
+        let coinbase = TxBuilder::coinbase(height)
+            .output_script(Script::paying(miner), value)
+            .build()
+            .unwrap();
+
+        let mut with_coinbase = vec![coinbase];
+        with_coinbase.extend(transactions);
+        Some(with_coinbase)
+    }
+
+    fn validate_coinbase(&self, b: &Block) -> Result<(), BlockValidationErr> {
+        let coinbase = b
+            .transactions
+            .first()
+            .and_then(|tx| tx.tx_data().ok())
+            .filter(|data| data.is_coinbase())
+            .ok_or(BlockValidationErr::MissingCoinbase)?;
+        if coinbase.coinbase_height != Some(b.height()) {
+            return Err(BlockValidationErr::InvalidCoinbaseHeight);
+        }
+        b.transactions[0]
+            .verify_id()
+            .map_err(|err| BlockValidationErr::InvalidTransaction { index: 0, err })
+    }
+
+    // fees are only known once the spent outputs are, i.e. when connecting `b`
+    fn validate_coinbase_value(&self, b: &Block, fees: Amount) -> Result<(), BlockValidationErr> {
+        let value = b.transactions[0]
+            .tx_data()
+            .ok()
+            .and_then(|coinbase| coinbase.output_value())
+            .ok_or(BlockValidationErr::ValueOverflow)?;
+        let max = self
+            .block_subsidy(b.height())
+            .checked_add(fees)
+            .ok_or(BlockValidationErr::ValueOverflow)?;
+        if value > max {
+            return Err(BlockValidationErr::CoinbaseValueTooHigh { max, got: value });
+        }
+        Ok(())
+    }
+
+    pub fn minning(&mut self) {
+        let Some(b) = self.proof_of_work() else {
+            eprintln!(
+                "action=minning status=fail err={:?}",
+                BlockValidationErr::NoMinerAddress
+            );
+            return;
+        };
+        if let Err(e) = self.add_block(b) {
+            eprintln!("action=minning status=fail err={:?}", e);
+            return;
+        }
         println!("action=minning status=success");
     }
 
-    pub fn is_valid(&mut self) -> bool {
-        self.chain
-            .iter_mut()
-            .skip(1)
-            .all(|b| b.hash() == b.gen_hash().unwrap())
+    pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
     }
 
     pub fn inspect(&self) {
@@ -157,9 +970,21 @@ impl BlockChain {
     }
 }
 
+// a node shutting down keeps the trxs it accepted since the last save
+impl Drop for BlockChain {
+    fn drop(&mut self) {
+        self.save_mem_pool();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::core::address::Address;
+    use crate::core::cyphers::{hash160, PublicKey, RecoverableSignature};
+    use crate::core::script::{OpCode, ScriptErr};
+    use crate::core::transaction::{LockTime, SigHash};
+    use crate::core::wallet::{CreateTxErr, Wallet, GAP_LIMIT};
 
     #[test]
     fn utxo() {
@@ -172,26 +997,1201 @@ mod tests {
         // peers help verify&confirm that trx :: minimum peers confirmation (where is that come from?)
 
         // balance come from :: previous
+        let mut a = Wallet::new(vec![]).unwrap();
+        let b = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &a);
+        assert_eq!(bc.balance_of(&a.address), MINNING_REWARD);
+
+        // B can't spend A's output
+        let mut not_owner = spend(&mut a, vec![coinbase.clone()], &addr("c"), "0.5", "0.5");
+        b.sign_transaction(&mut not_owner).unwrap();
+        assert_eq!(
+            bc.add_transaction(not_owner),
+            Err(TxValidationErr::InvalidScript(
+                coinbase.clone(),
+                ScriptErr::NotEqual
+            ))
+        );
+
+        // outputs worth more than the inputs
+        assert_eq!(
+            bc.add_transaction(spend(
+                &mut a,
+                vec![coinbase.clone()],
+                &addr("c"),
+                "1",
+                "0.5"
+            )),
+            Err(TxValidationErr::InsufficientInputs)
+        );
+
+        // A -> B, change back to A
+        let to_b = spend(&mut a, vec![coinbase.clone()], &b.address, "0.25", "0.625");
+        assert!(bc.add_transaction(to_b.clone()).is_ok());
+
+        // already spent by a pending trx (without paying more to replace it), unknown output
+        let missing = OutPoint {
+            tx_id: "missing".into(),
+            index: 0,
+        };
+        assert_eq!(
+            bc.add_transaction(spend(
+                &mut a,
+                vec![coinbase.clone()],
+                &addr("c"),
+                "0.5",
+                "0.5"
+            )),
+            Err(TxValidationErr::ReplacementFeeTooLow)
+        );
+        assert_eq!(
+            bc.add_transaction(spend(
+                &mut a,
+                vec![missing.clone()],
+                &addr("c"),
+                "0.5",
+                "0.5"
+            )),
+            Err(TxValidationErr::MissingInput(missing))
+        );
+
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        bc.minning();
+        assert_eq!(bc.latest_block().unwrap().transactions[1], to_b);
+        assert_eq!(bc.balance_of(&a.address), amount("0.625"));
+        assert_eq!(bc.balance_of(&b.address), amount("0.25"));
+        assert_eq!(bc.balance_of(&addr("miner")), amount("1.125"));
+        assert!(bc.mem_pool.is_empty());
+        assert!(bc.is_valid());
+
+        // spent on chain
+        assert_eq!(
+            bc.add_transaction(spend(
+                &mut a,
+                vec![coinbase.clone()],
+                &addr("c"),
+                "0.5",
+                "0.5"
+            )),
+            Err(TxValidationErr::MissingInput(coinbase.clone()))
+        );
+
+        // disconnecting gives the output back to A
+        bc.disconnect_block().unwrap();
+        assert_eq!(bc.balance_of(&a.address), MINNING_REWARD);
+        assert_eq!(bc.balance_of(&b.address), Amount::ZERO);
+        assert_eq!(bc.mem_pool.transactions(), vec![to_b]);
+    }
+
+    #[test]
+    fn mem_pool_rejects_unverified_trx() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let other = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &w);
+
+        let mut unsigned = spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5");
+        edit(&mut unsigned, |data| data.inputs[0].unlock_script.0.clear());
+        assert_eq!(
+            bc.add_transaction(unsigned),
+            Err(TxValidationErr::MissingSignature)
+        );
+
+        // the recovery id picks which key the signature recovers
+        let mut wrong_recovery = spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5");
+        edit(&mut wrong_recovery, |data| {
+            if let OpCode::Push(sig) = &mut data.inputs[0].unlock_script.0[0] {
+                sig[RecoverableSignature::SIZE - 1] ^= 1;
+            }
+        });
+        assert_eq!(
+            bc.add_transaction(wrong_recovery),
+            Err(TxValidationErr::InvalidScript(
+                coinbase.clone(),
+                ScriptErr::NotEqual
+            ))
+        );
+
+        // signed, but not by the owner of the output
+        let mut stolen = spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5");
+        other.sign_transaction(&mut stolen).unwrap();
+        assert_eq!(
+            bc.add_transaction(stolen),
+            Err(TxValidationErr::InvalidScript(
+                coinbase.clone(),
+                ScriptErr::NotEqual
+            ))
+        );
+
+        // data changed after signing, the signature recovers another key
+        let mut tampered = spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5");
+        edit(&mut tampered, |data| {
+            data.outputs[0].lock_script = Script::p2pkh_addr(&addr("b")).unwrap()
+        });
+        tampered.trx_id = tampered.compute_id().unwrap();
+        assert_eq!(
+            bc.add_transaction(tampered),
+            Err(TxValidationErr::InvalidScript(
+                coinbase.clone(),
+                ScriptErr::NotEqual
+            ))
+        );
+
+        // the key pushed along, a signature that doesn't verify for it
+        let mut forged = spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5");
+        edit(&mut forged, |data| {
+            let signature = w.sign_data(b"other data").unwrap();
+            data.inputs[0].unlock_script =
+                Script::p2pkh_unlock(&signature, SigHash::All, w.public_key());
+        });
+        assert_eq!(
+            bc.add_transaction(forged),
+            Err(TxValidationErr::InvalidScript(
+                coinbase.clone(),
+                ScriptErr::InvalidSignature
+            ))
+        );
+
+        // id that isn't the trx's own
+        let mut forged_id = spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5");
+        forged_id.trx_id = sha256::digest("forged");
+        assert_eq!(
+            bc.add_transaction(forged_id),
+            Err(TxValidationErr::InvalidTxId)
+        );
+
+        assert!(bc.mem_pool.is_empty());
+        assert!(bc
+            .add_transaction(spend(&mut w, vec![coinbase], &addr("a"), "0.5", "0.5"))
+            .is_ok());
+    }
+
+    #[test]
+    fn block_template_by_fee_rate() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let first = fund(&mut bc, &w);
+        let second = fund(&mut bc, &w);
+        let coinbase_only = bc.latest_block().unwrap().size();
+
+        let low = spend(&mut w, vec![first], &addr("a"), "0.5", "0.25");
+        let high = spend(&mut w, vec![second], &addr("b"), "0.5", "0");
+        bc.add_transaction(low.clone()).unwrap();
+        bc.add_transaction(high.clone()).unwrap();
+
+        // room for a single trx
+        bc.params.max_block_size = coinbase_only + high.size() + high.size() / 2;
+        bc.minning();
+        let b = bc.latest_block().unwrap();
+        assert_eq!(&b.transactions[1..], std::slice::from_ref(&high));
+        assert_eq!(bc.mem_pool.transactions(), vec![low.clone()]);
+
+        let too_large = bc.mine_on(b.prev_hash(), vec![low, high]).unwrap();
+        assert!(matches!(
+            bc.validate_block(&too_large, &bc.chain[..bc.chain.len() - 1]),
+            Err(BlockValidationErr::BlockTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn timelocks() {
+        let w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let first = fund(&mut bc, &w);
+        let second = fund(&mut bc, &w);
+        let signed = |builder: TxBuilder| {
+            let mut tx = builder.output(addr("a"), amount("1")).build().unwrap();
+            w.sign_transaction(&mut tx).unwrap();
+            tx
+        };
+
+        // absolute: not before block 4, the next one is 3
+        let after_4 = signed(
+            TxBuilder::new()
+                .input(first.clone())
+                .lock_time(LockTime::Height(4)),
+        );
+        assert_eq!(
+            bc.add_transaction(after_4.clone()),
+            Err(TxValidationErr::NotFinal)
+        );
+        let tip_hash = bc.latest_block().unwrap().hash();
+        let early = bc.mine_on(&tip_hash, vec![after_4.clone()]).unwrap();
+        assert!(matches!(
+            bc.add_block(early),
+            Err(BlockValidationErr::InvalidTransaction {
+                index: 1,
+                err: TxValidationErr::NotFinal
+            })
+        ));
+
+        let in_an_hour = Utc::now().timestamp() + 3600;
+        let later = signed(
+            TxBuilder::new()
+                .input(first)
+                .lock_time(LockTime::Time(in_an_hour)),
+        );
+        assert_eq!(bc.add_transaction(later), Err(TxValidationErr::NotFinal));
+
+        // relative: 3 blocks after `second` (block 2) was confirmed
+        let vesting = signed(TxBuilder::new().locked_input(second.clone(), 3));
+        assert_eq!(
+            bc.add_transaction(vesting.clone()),
+            Err(TxValidationErr::InputLocked(second.clone()))
+        );
+        let tip_hash = bc.latest_block().unwrap().hash();
+        let early = bc.mine_on(&tip_hash, vec![vesting.clone()]).unwrap();
+        assert!(matches!(
+            bc.add_block(early),
+            Err(BlockValidationErr::InvalidTransaction {
+                index: 1,
+                err: TxValidationErr::InputLocked(_)
+            })
+        ));
+
+        bc.minning(); // block 3
+        assert!(bc.add_transaction(after_4.clone()).is_ok());
+        assert_eq!(
+            bc.add_transaction(vesting.clone()),
+            Err(TxValidationErr::InputLocked(second))
+        );
+        bc.minning(); // block 4
+        assert!(bc.add_transaction(vesting.clone()).is_ok());
+        bc.minning();
+        assert_eq!(bc.latest_block().unwrap().transactions[1..], [vesting]);
+        assert!(bc.is_valid());
+    }
+
+    #[test]
+    fn wallet_pays_fee_and_change() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        fund(&mut bc, &w);
+        fund(&mut bc, &w);
+        let fee_rate = Amount::from_units(100);
+
+        let tx = w
+            .create_transaction(
+                bc.spendable_of(&w.address),
+                addr("b"),
+                amount("1.5"),
+                fee_rate,
+            )
+            .unwrap();
+        assert!(bc.add_transaction(tx.clone()).is_ok());
+        let fee = bc.mem_pool.iter().next().unwrap().fee;
+        assert!(fee >= fee_rate.checked_mul(tx.size() as u64).unwrap());
+
+        // both coins are taken by the pending trx
+        assert!(bc.spendable_of(&w.address).is_empty());
+        assert_eq!(
+            w.create_transaction(
+                bc.spendable_of(&w.address),
+                addr("b"),
+                amount("0.1"),
+                fee_rate
+            ),
+            Err(CreateTxErr::InsufficientFunds {
+                needed: amount("0.1"),
+                available: Amount::ZERO
+            })
+        );
+
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        bc.minning();
+        assert_eq!(bc.balance_of(&addr("b")), amount("1.5"));
+        assert_eq!(bc.spendable_of(&w.address).len(), 1);
+        let change = amount("0.5").checked_sub(fee).unwrap();
+        assert_eq!(bc.balance_of(&w.address), change);
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &w);
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+
+        let stuck = w
+            .create_transaction(
+                bc.spendable_of(&w.address),
+                addr("b"),
+                amount("0.5"),
+                Amount::from_units(1),
+            )
+            .unwrap();
+        bc.add_transaction(stuck.clone()).unwrap();
+        let pending = bc.mem_pool.get(&stuck.trx_id).unwrap().clone();
+
+        // a conflict paying less, or one that wasn't opted in to be replaced
+        assert_eq!(
+            bc.add_transaction(spend(
+                &mut w,
+                vec![coinbase.clone()],
+                &addr("c"),
+                "0.5",
+                "0.5"
+            )),
+            Err(TxValidationErr::ReplacementFeeTooLow)
+        );
+
+        let bumped = w.bump_fee(&pending, Amount::from_units(10)).unwrap();
+        assert_ne!(bumped.trx_id, stuck.trx_id);
+        bc.add_transaction(bumped.clone()).unwrap();
+        assert_eq!(bc.mem_pool.transactions(), vec![bumped.clone()]);
+        let entry = bc.mem_pool.get(&bumped.trx_id).unwrap();
+        assert!(entry.fee > pending.fee);
+        assert_eq!(entry.cmp_fee_rate(&pending), std::cmp::Ordering::Greater);
+
+        // this one doesn't opt in, nothing replaces it
+        let mut final_tx = TxBuilder::new()
+            .input(coinbase.clone())
+            .output(addr("b"), amount("0.5"))
+            .build()
+            .unwrap();
+        w.sign_transaction(&mut final_tx).unwrap();
+        bc.add_transaction(final_tx.clone()).unwrap();
+        let pending = bc.mem_pool.get(&final_tx.trx_id).unwrap().clone();
+        // no change left to pay more with
+        assert!(matches!(
+            w.bump_fee(&pending, Amount::from_units(10)),
+            Err(CreateTxErr::InsufficientFunds { .. })
+        ));
+        assert_eq!(
+            bc.add_transaction(spend(
+                &mut w,
+                vec![coinbase.clone()],
+                &addr("c"),
+                "0.1",
+                "0"
+            )),
+            Err(TxValidationErr::DoubleSpend(coinbase))
+        );
+
+        bc.minning();
+        assert_eq!(bc.latest_block().unwrap().transactions[1..], [final_tx]);
+        assert_eq!(bc.balance_of(&addr("b")), amount("0.5"));
+        assert!(bc.mem_pool.is_empty());
+    }
+
+    #[test]
+    fn hd_wallet_spends_every_address() {
+        let mut w = Wallet::from_seed(&[3u8; 32]).unwrap();
+        let receive = w.fresh_address(false).unwrap();
+        let mut bc = BlockChain::new();
+        fund(&mut bc, &w);
+        bc.set_block_chain_address(&receive).unwrap();
+        bc.minning();
+        let unspent = |bc: &BlockChain, w: &Wallet| -> Vec<(OutPoint, UtxoEntry)> {
+            w.addresses()
+                .iter()
+                .flat_map(|address| bc.spendable_of(address))
+                .collect()
+        };
+
+        // a coin of each address, each input signed with its own key
+        let fee_rate = Amount::from_units(10);
+        let tx = w
+            .create_transaction(unspent(&bc, &w), addr("b"), amount("1.5"), fee_rate)
+            .unwrap();
+        let data = tx.tx_data().unwrap();
+        let mut signers: Vec<String> = (0..2).filter_map(|i| data.signer_address(i)).collect();
+        signers.sort();
+        let mut owners = vec![w.address.clone(), receive.clone()];
+        owners.sort();
+        assert_eq!(signers, owners);
+
+        // change to the first change address, tracked from then on
+        let change = w.derive(true, 0).unwrap().address;
+        assert!(data.outputs[1].pays_to(&change));
+        assert!(w.addresses().contains(&change));
+        assert_eq!(
+            w.fresh_address(true).unwrap(),
+            w.derive(true, 1).unwrap().address
+        );
+        bc.add_transaction(tx.clone()).unwrap();
+
+        // bumped out of the change, still signed by both keys
+        let pending = bc.mem_pool.get(&tx.trx_id).unwrap().clone();
+        let bumped = w.bump_fee(&pending, Amount::from_units(20)).unwrap();
+        let bumped_data = bumped.tx_data().unwrap();
+        assert_eq!(bumped_data.outputs.len(), 2);
+        assert!(bumped_data.outputs[1].value < data.outputs[1].value);
+        bc.add_transaction(bumped).unwrap();
+
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        bc.minning();
+        assert_eq!(bc.balance_of(&addr("b")), amount("1.5"));
+        assert_eq!(bc.balance_of(&change), bumped_data.outputs[1].value);
+        assert_eq!(unspent(&bc, &w).len(), 1);
+    }
+
+    #[test]
+    fn restored_wallet_spends_change() {
+        let mut w = Wallet::generate_mnemonic(12, "").unwrap();
+        let phrase = w.mnemonic().unwrap();
+        let mut bc = BlockChain::new();
+        let receive = w.fresh_address(false).unwrap();
+        for _ in 0..GAP_LIMIT / 2 {
+            w.fresh_address(false); // handed out, never paid
+        }
+        let late = w.fresh_address(false).unwrap();
+        bc.set_block_chain_address(&receive).unwrap();
+        bc.minning();
+        bc.set_block_chain_address(&late).unwrap();
+        bc.minning();
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        let unspent = |bc: &BlockChain, w: &Wallet| -> Vec<(OutPoint, UtxoEntry)> {
+            w.addresses()
+                .iter()
+                .flat_map(|address| bc.spendable_of(address))
+                .collect()
+        };
+
+        let fee_rate = Amount::from_units(10);
+        let tx = w
+            .create_transaction(unspent(&bc, &w), addr("b"), amount("1.5"), fee_rate)
+            .unwrap();
+        bc.add_transaction(tx).unwrap();
+        bc.minning();
+        let change = w.derive(true, 0).unwrap().address;
+        assert!(bc.balance_of(&change) > Amount::ZERO);
+
+        // only its first address is known until the others are found on the chain
+        let mut restored = Wallet::from_mnemonic(&phrase, "").unwrap();
+        assert!(unspent(&bc, &restored).is_empty());
+        assert_eq!(restored.discover(|address| bc.is_used(address)), 3);
+        for address in [&receive, &late, &change] {
+            assert!(restored.addresses().contains(address));
+        }
+        // the next ones are fresh
+        assert_eq!(
+            restored.fresh_address(true),
+            w.derive(true, 1).map(|w| w.address)
+        );
+
+        let tx = restored
+            .create_transaction(unspent(&bc, &restored), addr("c"), amount("0.25"), fee_rate)
+            .unwrap();
+        let data = tx.tx_data().unwrap();
+        assert_eq!(data.signer_address(0), Some(change));
+        assert!(bc.add_transaction(tx).is_ok());
+    }
+
+    #[test]
+    fn multisig_treasury() {
+        let owners: Vec<Wallet> = (0..3).map(|_| Wallet::new(vec![]).unwrap()).collect();
+        let mut outsider = Wallet::new(vec![]).unwrap();
+        let keys: Vec<PublicKey> = owners.iter().map(|w| w.public_key().clone()).collect();
+        let redeem_script = Script::multisig(2, &keys).unwrap();
+        let treasury = redeem_script.script_hash_address();
+        assert!(Script::multisig(4, &keys).is_none());
+
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &outsider);
+        let deposit = spend(&mut outsider, vec![coinbase], &treasury, "1", "0");
+        assert!(bc.add_transaction(deposit.clone()).is_ok());
+        bc.minning();
+        assert_eq!(bc.balance_of(&treasury), amount("1"));
+
+        let funds = OutPoint {
+            tx_id: deposit.trx_id,
+            index: 0,
+        };
+        let mut payout = TxBuilder::new()
+            .input(funds.clone())
+            .output(addr("vendor"), amount("0.75"))
+            .build()
+            .unwrap();
+        assert!(outsider
+            .sign_multisig(&mut payout, 0, &redeem_script)
+            .is_err());
+
+        // one approval isn't enough
+        owners[2]
+            .sign_multisig(&mut payout, 0, &redeem_script)
+            .unwrap();
+        assert_eq!(
+            bc.add_transaction(payout.clone()),
+            Err(TxValidationErr::InvalidScript(
+                funds.clone(),
+                ScriptErr::StackUnderflow
+            ))
+        );
+        // signing twice doesn't count twice
+        owners[2]
+            .sign_multisig(&mut payout, 0, &redeem_script)
+            .unwrap();
+        assert!(bc.add_transaction(payout.clone()).is_err());
+
+        owners[0]
+            .sign_multisig(&mut payout, 0, &redeem_script)
+            .unwrap();
+        assert!(bc.add_transaction(payout.clone()).is_ok());
+
+        bc.minning();
+        assert_eq!(bc.txs_of_addr(treasury.clone()).len(), 2);
+        assert_eq!(bc.balance_of(&treasury), Amount::ZERO);
+        assert_eq!(bc.balance_of(&addr("vendor")), amount("0.75"));
+        assert!(bc.is_valid());
+    }
+
+    #[test]
+    fn crowdfunding() {
+        let backers: Vec<Wallet> = (0..2).map(|_| Wallet::new(vec![]).unwrap()).collect();
+        let mut bc = BlockChain::new();
+        let coins: Vec<OutPoint> = backers.iter().map(|w| fund(&mut bc, w)).collect();
+        let reward = bc.balance_of(&backers[0].address);
+        let goal = reward.checked_mul(2).unwrap();
+
+        // the first backer pledges for the goal, anyone can add an input to it
+        let mut pledge = TxBuilder::new()
+            .input(coins[0].clone())
+            .output(addr("project"), goal)
+            .build()
+            .unwrap();
+        backers[0]
+            .sign_input(&mut pledge, 0, SigHash::AllAnyoneCanPay)
+            .unwrap();
+        assert_eq!(
+            bc.add_transaction(pledge.clone()),
+            Err(TxValidationErr::InsufficientInputs)
+        );
+
+        // a signature of the whole trx breaks when someone else chips in:
+        // it recovers some other key than the backer's
+        let mut committed = pledge.clone();
+        backers[0].sign_transaction(&mut committed).unwrap();
+        backers[1]
+            .contribute(&mut committed, coins[1].clone())
+            .unwrap();
+        assert_eq!(
+            bc.add_transaction(committed),
+            Err(TxValidationErr::InvalidScript(
+                coins[0].clone(),
+                ScriptErr::NotEqual
+            ))
+        );
+
+        backers[1]
+            .contribute(&mut pledge, coins[1].clone())
+            .unwrap();
+        assert!(bc.add_transaction(pledge).is_ok());
+        bc.minning();
+        assert_eq!(bc.balance_of(&addr("project")), goal);
+        assert!(bc.is_valid());
     }
 
     #[test]
     fn test_is_valid() {
         let mut bc = BlockChain::new();
 
-        // create block 1
-        let genesis_hash = bc.latest_block().unwrap().hash();
-        let block_1 = bc.create_block(genesis_hash, 0).unwrap();
+        // nothing to pay the reward to, nothing is mined
+        bc.minning();
+        assert_eq!(bc.chain.len(), 1);
+        assert_eq!(
+            bc.set_block_chain_address("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"),
+            Err(AddressErr::InvalidChecksum)
+        );
+        bc.minning();
+        assert_eq!(bc.chain.len(), 1);
 
-        // create block 2
-        let block_1_hash = block_1.hash();
-        let block_2 = bc.create_block(block_1_hash, 1).unwrap();
-        let block_2_hash = block_2.hash();
+        // mine block 1, 2
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        bc.minning();
+        bc.minning();
 
         assert!(&bc.is_valid());
 
+        // a block that doesn't meet the required difficulty
+        let genesis_hash = bc.chain[0].hash();
+        let mut unmined = BlockChain::new();
+        assert!(matches!(
+            unmined.create_block(genesis_hash.clone(), 0),
+            Err(BlockValidationErr::NoMinerAddress)
+        ));
+        unmined.set_block_chain_address(&addr("miner")).unwrap();
+        assert!(matches!(
+            unmined.create_block(ZERO_HASH.into(), 0),
+            Err(BlockValidationErr::UnknownParent)
+        ));
+        let created = unmined.create_block(genesis_hash, 0).unwrap().hash();
+        assert_eq!(unmined.latest_block().unwrap().hash(), created);
+        assert!(unmined.is_valid());
+        unmined.chain[1].header.difficulty = 0;
+        assert!(!unmined.is_valid());
+
         bc.block_nth(1).unwrap().header.time_stamp = chrono::Utc::now().timestamp();
         bc.block_nth(1).unwrap().header.nonce = 90;
         println!("\n\n-----------\n\n");
         assert_eq!(&bc.is_valid(), &false);
     }
+
+    fn mine(b: &mut Block) {
+        b.header.nonce = 0;
+        while !(b.gen_hash().is_ok() && b.meets_difficulty()) {
+            b.header.nonce += 1;
+        }
+    }
+
+    fn amount(s: &str) -> Amount {
+        s.parse().unwrap()
+    }
+
+    // P2PKH address no one has the key of
+    fn addr(name: &str) -> String {
+        Address::PubKeyHash(hash160(name.as_bytes())).to_string()
+    }
+
+    // mine a block paying `w`, returns its coinbase output
+    fn fund(bc: &mut BlockChain, w: &Wallet) -> OutPoint {
+        bc.set_block_chain_address(&w.address).unwrap();
+        bc.minning();
+        OutPoint {
+            tx_id: bc.latest_block().unwrap().transactions[0].trx_id.clone(),
+            index: 0,
+        }
+    }
+
+    fn spend(
+        w: &mut Wallet,
+        inputs: Vec<OutPoint>,
+        receiver_addr: &str,
+        value: &str,
+        change: &str,
+    ) -> Transaction {
+        let mut tx = w
+            .build_transaction(inputs, receiver_addr.into(), amount(value), amount(change))
+            .unwrap();
+        w.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    fn unknown_out() -> OutPoint {
+        OutPoint {
+            tx_id: "unknown".into(),
+            index: 0,
+        }
+    }
+
+    // change the data of a signed trx
+    fn edit(tx: &mut Transaction, f: impl FnOnce(&mut TransactionData)) {
+        let mut data = tx.tx_data().unwrap();
+        f(&mut data);
+        tx.data = bincode::serialize(&data).unwrap();
+    }
+
+    #[test]
+    fn fork_choice_by_cumulative_work() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &w);
+        let fork_hash = bc.latest_block().unwrap().hash();
+
+        let to_a = spend(&mut w, vec![coinbase], &addr("a"), "0.5", "0.5");
+        bc.add_transaction(to_a.clone()).unwrap();
+        bc.minning();
+        let a2_hash = bc.latest_block().unwrap().hash();
+        assert!(bc.mem_pool.is_empty());
+
+        // competing branch of the same work: first seen tip is kept
+        let b2 = bc.mine_on(&fork_hash, vec![]).unwrap();
+        let b2_hash = b2.hash();
+        bc.add_block(b2.clone()).unwrap();
+        assert_eq!(bc.latest_block().unwrap().hash(), a2_hash);
+        assert!(matches!(
+            bc.add_block(b2),
+            Err(BlockValidationErr::Duplicate)
+        ));
+
+        // heavier branch: reorg, disconnected trxs are pending again
+        let b3 = bc.mine_on(&b2_hash, vec![]).unwrap();
+        let b3_hash = b3.hash();
+        bc.add_block(b3).unwrap();
+        assert_eq!(bc.chain.len(), 4);
+        assert_eq!(bc.chain[2].hash(), b2_hash);
+        assert!(bc.side_blocks.contains_key(&a2_hash));
+        assert_eq!(bc.mem_pool.transactions(), vec![to_a.clone()]);
+        assert_eq!(bc.balance_of(&addr("a")), Amount::ZERO);
+        assert!(bc.is_valid());
+
+        // extending the old branch switches back once it's heavier
+        let a3 = bc.mine_on(&a2_hash, vec![]).unwrap();
+        let a3_hash = a3.hash();
+        bc.add_block(a3).unwrap();
+        assert_eq!(bc.latest_block().unwrap().hash(), b3_hash);
+        let a4 = bc.mine_on(&a3_hash, vec![]).unwrap();
+        bc.add_block(a4).unwrap();
+        assert_eq!(bc.chain[2].hash(), a2_hash);
+        assert!(bc.mem_pool.is_empty());
+        assert_eq!(bc.balance_of(&addr("a")), amount("0.5"));
+    }
+
+    #[test]
+    fn nodes_share_genesis() {
+        let mut a = BlockChain::new();
+        let mut b = BlockChain::new();
+        assert_eq!(a.chain[0].hash(), b.chain[0].hash());
+
+        // blocks mined by one node extend the other's chain
+        a.set_block_chain_address(&addr("miner")).unwrap();
+        a.minning();
+        b.add_block(a.latest_block().unwrap().clone()).unwrap();
+        assert_eq!(
+            b.latest_block().unwrap().hash(),
+            a.latest_block().unwrap().hash()
+        );
+
+        let other = BlockChain::with_params(ChainParams {
+            genesis_nonce: 1,
+            ..ChainParams::default()
+        });
+        assert_ne!(other.chain[0].hash(), a.chain[0].hash());
+    }
+
+    #[test]
+    fn reorg_to_invalid_branch_is_rolled_back() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &w);
+        let fork_hash = bc.latest_block().unwrap().hash();
+        bc.minning();
+        bc.minning();
+        let hashes: Vec<String> = bc.chain.iter().map(|b| b.hash()).collect();
+
+        // spends the same output twice, only found out when connecting it
+        let double_spend = vec![
+            spend(&mut w, vec![coinbase.clone()], &addr("a"), "0.5", "0.5"),
+            spend(&mut w, vec![coinbase], &addr("b"), "0.5", "0.5"),
+        ];
+        let c2 = bc.mine_on(&fork_hash, double_spend).unwrap();
+        let c2_hash = c2.hash();
+        bc.add_block(c2).unwrap();
+        let c3 = bc.mine_on(&c2_hash, vec![]).unwrap();
+        let c3_hash = c3.hash();
+        bc.add_block(c3).unwrap();
+        bc.set_block_chain_address(&addr("other")).unwrap();
+        let other_c3 = bc.mine_on(&c2_hash, vec![]).unwrap();
+        bc.add_block(other_c3.clone()).unwrap();
+        assert_eq!(bc.side_blocks.len(), 3);
+
+        let c4 = bc.mine_on(&c3_hash, vec![]).unwrap();
+        assert!(matches!(
+            bc.add_block(c4.clone()),
+            Err(BlockValidationErr::InvalidTransaction { index: 2, .. })
+        ));
+
+        let after: Vec<String> = bc.chain.iter().map(|b| b.hash()).collect();
+        assert_eq!(after, hashes);
+        assert_eq!(bc.balance_of(&w.address), amount("3"));
+        assert!(bc.is_valid());
+
+        // the whole branch is gone, none of it is a candidate tip anymore
+        assert!(bc.side_blocks.is_empty());
+        assert_eq!(bc.chain_work.len(), hashes.len());
+        for b in [c4, other_c3] {
+            assert!(!matches!(
+                bc.add_block(b),
+                Ok(()) | Err(BlockValidationErr::Duplicate)
+            ));
+        }
+    }
+
+    #[test]
+    fn reopen_persisted_chain() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let dir = crate::core::storage::temp_dir();
+
+        let mut bc = BlockChain::open(&dir, ChainParams::default()).unwrap();
+        let coinbase = fund(&mut bc, &w);
+        let genesis_hash = bc.chain[0].hash();
+        bc.set_block_chain_address(&addr("side")).unwrap();
+        let side = bc.mine_on(&genesis_hash, vec![]).unwrap();
+        let side_hash = side.hash();
+        bc.add_block(side).unwrap();
+        bc.set_block_chain_address(&w.address).unwrap();
+        bc.minning();
+        let pending = spend(&mut w, vec![coinbase], &addr("pending"), "0.5", "0.5");
+        bc.add_transaction(pending.clone()).unwrap();
+        assert!(bc.mem_pool_changed); // not saved per trx, but on drop
+        let hashes: Vec<String> = bc.chain.iter().map(|b| b.hash()).collect();
+        drop(bc);
+
+        let bc = BlockChain::open(&dir, ChainParams::default()).unwrap();
+        let reopened: Vec<String> = bc.chain.iter().map(|b| b.hash()).collect();
+        assert_eq!(reopened, hashes);
+        assert!(bc.side_blocks.contains_key(&side_hash));
+        assert_eq!(bc.mem_pool.transactions(), vec![pending]);
+        assert_eq!(bc.balance_of(&w.address), amount("2"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_with_other_genesis() {
+        let dir = crate::core::storage::temp_dir();
+        let genesis_hash = BlockChain::open(&dir, ChainParams::default())
+            .unwrap()
+            .chain[0]
+            .hash();
+
+        let params = ChainParams {
+            genesis_nonce: 1,
+            ..ChainParams::default()
+        };
+        match BlockChain::open(&dir, params) {
+            Err(StorageErr::GenesisMismatch { stored, .. }) => assert_eq!(stored, genesis_hash),
+            other => panic!("opened another chain's dir: {:?}", other.map(|_| ())),
+        }
+        // the dir is still the default chain's
+        assert!(BlockChain::open(&dir, ChainParams::default()).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_block_isnt_stored() {
+        let miner = Wallet::new(vec![]).unwrap();
+        let dir = crate::core::storage::temp_dir();
+        let mut bc = BlockChain::open(&dir, ChainParams::default()).unwrap();
+
+        // valid until connected: it pays more than the subsidy
+        let inflated = TxBuilder::coinbase(1)
+            .output(miner.address.clone(), amount("2"))
+            .build()
+            .unwrap();
+        let mut b = Block::new(bc.chain[0].hash(), 0, 1, vec![inflated]);
+        b.header.difficulty = bc.required_difficulty(1);
+        mine(&mut b);
+        assert!(matches!(
+            bc.add_block(b.clone()),
+            Err(BlockValidationErr::CoinbaseValueTooHigh { .. })
+        ));
+        assert!(matches!(
+            bc.add_block(b.clone()),
+            Err(BlockValidationErr::KnownInvalid)
+        ));
+        // nor is anything built on it
+        let mut child = Block::new(b.hash(), 0, 2, vec![]);
+        child.gen_hash().unwrap();
+        assert!(matches!(
+            bc.add_block(child),
+            Err(BlockValidationErr::KnownInvalid)
+        ));
+        bc.set_block_chain_address(&miner.address).unwrap();
+        bc.minning();
+        drop(bc);
+
+        // only what was accepted was stored: genesis and the block mined
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|stored| stored.hash() != b.hash()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reject_unlinked_or_unproven_block() {
+        let mut bc = BlockChain::new();
+
+        let orphan = Block::new(ZERO_HASH.replace('0', "1"), 0, 1, vec![]);
+        assert!(matches!(
+            bc.add_block(orphan),
+            Err(BlockValidationErr::UnknownParent)
+        ));
+
+        let genesis_hash = bc.chain[0].hash();
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        let mut unproven = bc.mine_on(&genesis_hash, vec![]).unwrap();
+        unproven.header.difficulty -= 1;
+        unproven.gen_hash();
+        assert!(matches!(
+            bc.add_block(unproven),
+            Err(BlockValidationErr::InvalidDifficulty { .. })
+        ));
+    }
+
+    #[test]
+    fn validate_block_rules() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        bc.set_block_chain_address(&addr("miner")).unwrap();
+        let genesis_hash = bc.chain[0].hash();
+        let valid = bc
+            .mine_on(
+                &genesis_hash,
+                vec![spend(&mut w, vec![unknown_out()], &addr("a"), "1", "0")],
+            )
+            .unwrap();
+        assert!(bc.validate_block(&valid, &bc.chain).is_ok());
+
+        // re-mine after breaking one rule, so only that rule fails
+        let remine = |mut b: Block| {
+            mine(&mut b);
+            bc.validate_block(&b, &bc.chain)
+        };
+
+        let mut b = valid.clone();
+        b.header.nonce += 1;
+        assert!(matches!(
+            bc.validate_block(&b, &bc.chain),
+            Err(BlockValidationErr::InvalidHash)
+        ));
+
+        let mut b = valid.clone();
+        while b.gen_hash().is_ok() && b.meets_difficulty() {
+            b.header.nonce += 1;
+        }
+        assert!(matches!(
+            bc.validate_block(&b, &bc.chain),
+            Err(BlockValidationErr::InvalidProof)
+        ));
+
+        let relinked = |prev_hash: &str, height: u64| {
+            let mut b = Block::new(prev_hash.into(), 0, height, valid.transactions.clone());
+            b.header.difficulty = valid.header.difficulty;
+            b
+        };
+        assert!(matches!(
+            remine(relinked(&genesis_hash, 5)),
+            Err(BlockValidationErr::InvalidHeight {
+                expected: 1,
+                got: 5
+            })
+        ));
+        assert!(matches!(
+            remine(relinked(ZERO_HASH, 1)),
+            Err(BlockValidationErr::InvalidPrevHash)
+        ));
+
+        let mut b = valid.clone();
+        b.header.time_stamp = bc.chain[0].header.time_stamp - 1;
+        assert!(matches!(remine(b), Err(BlockValidationErr::TimeTooOld)));
+
+        let mut b = valid.clone();
+        b.header.time_stamp = Utc::now().timestamp() + MAX_FUTURE_BLOCK_TIME + 60;
+        assert!(matches!(remine(b), Err(BlockValidationErr::TimeTooNew)));
+
+        let mut b = valid.clone();
+        b.transactions
+            .push(spend(&mut w, vec![unknown_out()], &addr("b"), "1", "0"));
+        assert!(matches!(
+            remine(b),
+            Err(BlockValidationErr::InvalidMerkleRoot)
+        ));
+
+        let mut b = valid.clone();
+        b.transactions[1].trx_id = sha256::digest("forged");
+        b.commit_transactions();
+        assert!(matches!(
+            remine(b),
+            Err(BlockValidationErr::InvalidTransaction {
+                index: 1,
+                err: TxValidationErr::InvalidTxId
+            })
+        ));
+
+        // the unlocking script isn't part of the ids, the witness root commits it
+        let mut b = valid.clone();
+        edit(&mut b.transactions[1], |data| {
+            data.inputs[0].unlock_script.0.clear()
+        });
+        assert!(matches!(
+            remine(b.clone()),
+            Err(BlockValidationErr::InvalidWitnessRoot)
+        ));
+        b.commit_transactions();
+        assert!(matches!(
+            remine(b),
+            Err(BlockValidationErr::InvalidTransaction {
+                index: 1,
+                err: TxValidationErr::MissingSignature
+            })
+        ));
+    }
+
+    #[test]
+    fn malleated_copy_doesnt_shadow_block() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let other = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let coinbase = fund(&mut bc, &w);
+        let tip_hash = bc.latest_block().unwrap().hash();
+        let honest = bc
+            .mine_on(
+                &tip_hash,
+                vec![spend(&mut w, vec![coinbase], &addr("a"), "0.5", "0.5")],
+            )
+            .unwrap();
+
+        // same header, so same hash, with another unlocking script
+        let mut malleated = honest.clone();
+        other
+            .sign_transaction(&mut malleated.transactions[1])
+            .unwrap();
+        assert_eq!(malleated.compute_hash().unwrap(), honest.hash());
+        assert!(matches!(
+            bc.add_block(malleated),
+            Err(BlockValidationErr::InvalidWitnessRoot)
+        ));
+
+        bc.add_block(honest.clone()).unwrap();
+        assert_eq!(bc.latest_block().unwrap().hash(), honest.hash());
+        assert_eq!(bc.balance_of(&addr("a")), amount("0.5"));
+    }
+
+    #[test]
+    fn repeated_trxs_dont_shadow_block() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let first = fund(&mut bc, &w);
+        let second = fund(&mut bc, &w);
+        let tip_hash = bc.latest_block().unwrap().hash();
+        let honest = bc
+            .mine_on(
+                &tip_hash,
+                vec![
+                    spend(&mut w, vec![first], &addr("a"), "1", "0"),
+                    spend(&mut w, vec![second], &addr("b"), "1", "0"),
+                ],
+            )
+            .unwrap();
+
+        // the last trx repeated under the same header, or committed to
+        let mut mutated = honest.clone();
+        mutated.transactions.push(honest.transactions[2].clone());
+        assert_eq!(mutated.compute_hash().unwrap(), honest.hash());
+        assert!(matches!(
+            bc.add_block(mutated.clone()),
+            Err(BlockValidationErr::InvalidMerkleRoot)
+        ));
+        mutated.commit_transactions();
+        mine(&mut mutated);
+        assert!(matches!(
+            bc.validate_block(&mutated, &bc.chain),
+            Err(BlockValidationErr::DuplicateTransaction { index: 3 })
+        ));
+
+        bc.add_block(honest.clone()).unwrap();
+        assert_eq!(bc.latest_block().unwrap().hash(), honest.hash());
+        assert_eq!(bc.balance_of(&addr("b")), amount("1"));
+    }
+
+    #[test]
+    fn coinbase_reward() {
+        let miner = Wallet::new(vec![]).unwrap();
+        let mut w = Wallet::new(vec![]).unwrap();
+        let mut bc = BlockChain::new();
+        let funding = fund(&mut bc, &w);
+        bc.set_block_chain_address(&miner.address).unwrap();
+        bc.add_transaction(spend(&mut w, vec![funding], &addr("a"), "0.5", "0.25"))
+            .unwrap();
+        bc.minning();
+
+        let b = bc.latest_block().unwrap();
+        let coinbase = b.transactions[0].tx_data().unwrap();
+        assert!(coinbase.is_coinbase());
+        assert_eq!(
+            coinbase.outputs[0].recipient_addr(),
+            Some(miner.address.clone())
+        );
+        assert_eq!(coinbase.output_value(), Some(amount("1.25")));
+        assert_eq!(coinbase.coinbase_height, Some(2));
+
+        // only one coinbase, first, paying at most subsidy + fees
+        let genesis_hash = bc.chain[0].hash();
+        let mut b = bc.mine_on(&genesis_hash, vec![]).unwrap();
+        b.transactions.remove(0);
+        b.commit_transactions();
+        mine(&mut b);
+        assert!(matches!(
+            bc.validate_block(&b, &bc.chain[..1]),
+            Err(BlockValidationErr::MissingCoinbase)
+        ));
+
+        // fees are only known when connecting the block
+        let mut fresh = BlockChain::new();
+        let inflated = TxBuilder::coinbase(1)
+            .output(miner.address.clone(), amount("2"))
+            .build()
+            .unwrap();
+        let mut b = Block::new(fresh.chain[0].hash(), 0, 1, vec![inflated]);
+        b.header.difficulty = fresh.required_difficulty(1);
+        mine(&mut b);
+        assert!(fresh.validate_block(&b, &fresh.chain).is_ok());
+        assert!(matches!(
+            fresh.add_block(b),
+            Err(BlockValidationErr::CoinbaseValueTooHigh { .. })
+        ));
+        assert_eq!(fresh.chain.len(), 1);
+
+        let extra = TxBuilder::coinbase(1)
+            .output(miner.address.clone(), amount("0.5"))
+            .build()
+            .unwrap();
+        let b = bc.mine_on(&genesis_hash, vec![extra]).unwrap();
+        assert!(matches!(
+            bc.validate_block(&b, &bc.chain[..1]),
+            Err(BlockValidationErr::UnexpectedCoinbase { index: 1 })
+        ));
+    }
+
+    #[test]
+    fn subsidy_halving_and_supply_cap() {
+        let mut bc = BlockChain::with_params(ChainParams {
+            initial_subsidy: amount("1"),
+            halving_interval: 2,
+            max_supply: amount("100"),
+            ..ChainParams::default()
+        });
+        let subsidies: Vec<String> = (0..7).map(|h| bc.block_subsidy(h).to_string()).collect();
+        assert_eq!(subsidies, ["0", "1", "0.5", "0.5", "0.25", "0.25", "0.125"]);
+        assert_eq!(bc.block_subsidy(2 * MAX_HALVINGS), Amount::ZERO);
+
+        // 1.0 + 0.5 + 0.5 issued, only 0.25 left
+        bc.params.max_supply = amount("2.25");
+        assert_eq!(bc.block_subsidy(4), amount("0.25"));
+        bc.params.max_supply = amount("2.125");
+        assert_eq!(bc.block_subsidy(4), amount("0.125"));
+        assert_eq!(bc.block_subsidy(5), Amount::ZERO);
+
+        bc.params.halving_interval = 0;
+        bc.params.max_supply = amount("100");
+        assert_eq!(bc.block_subsidy(100), amount("1"));
+        assert_eq!(bc.block_subsidy(101), Amount::ZERO);
+    }
+
+    #[test]
+    fn retarget_difficulty() {
+        let params = ChainParams {
+            target_block_time: 10,
+            retarget_interval: 4,
+            initial_difficulty: 8,
+            ..ChainParams::default()
+        };
+        let block_at = |time_stamp: i64, difficulty: u32| {
+            let mut b = Block::new("prev_hash".into(), 0, 0, vec![]);
+            b.header.time_stamp = time_stamp;
+            b.header.difficulty = difficulty;
+            b
+        };
+
+        let mut bc = BlockChain::with_params(params.clone());
+        bc.chain = (0..4).map(|i| block_at(i * 10, 8)).collect();
+        assert_eq!(bc.required_difficulty(3), 8); // not a retarget height
+        assert_eq!(bc.required_difficulty(4), 8); // on target
+
+        bc.chain = (0..4).map(|i| block_at(i * 2, 8)).collect();
+        assert_eq!(bc.required_difficulty(4), 10); // 5x too fast, capped at 4x
+
+        bc.chain = (0..4).map(|i| block_at(i * 5, 8)).collect();
+        assert_eq!(bc.required_difficulty(4), 9); // 2x too fast
+
+        bc.chain = (0..4).map(|i| block_at(i * 25, 8)).collect();
+        assert_eq!(bc.required_difficulty(4), 7); // 2.5x too slow
+
+        bc.chain = (0..4).map(|i| block_at(i * 100, 2)).collect();
+        assert_eq!(bc.required_difficulty(4), MIN_DIFFICULTY);
+    }
 }
@@ -1,8 +1,12 @@
+use super::address::Address;
 use k256::ecdsa::signature::{Signer, Verifier};
 use k256::ecdsa::Error as EcdsaErr;
 use k256::ecdsa::{RecoveryId, Signature as EcdsaSignature, SigningKey, VerifyingKey};
 use k256::elliptic_curve::rand_core::OsRng;
 use k256::SecretKey;
+use ripemd::{Digest, Ripemd160};
+use std::fmt;
+use zeroize::Zeroize;
 
 pub trait Encoder {
     fn encode(&self) -> Result<Vec<u8>, String>;
@@ -13,11 +17,18 @@ pub trait Decoder {
     fn decode(&self, encoded: &Vec<u8>) -> Result<Box<Self>, String>;
 }
 
-#[derive(Debug)]
+#[derive(Clone)]
 pub struct PrivateKey {
     key_bytes: [u8; 32],
 }
 
+// never prints the key
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrivateKey").finish_non_exhaustive()
+    }
+}
+
 impl PrivateKey {
     pub fn generate() -> Result<Self, String> {
         let pk = SecretKey::random(&mut OsRng);
@@ -33,6 +44,12 @@ impl PrivateKey {
         }
     }
 
+    // a 32 bytes secret, as decrypted from a keystore
+    pub fn from_bytes(key_bytes: [u8; 32]) -> Result<Self, EcdsaErr> {
+        SigningKey::from_slice(&key_bytes)?;
+        Ok(Self { key_bytes })
+    }
+
     pub fn public_key(&self) -> PublicKey {
         PublicKey::from(&self)
     }
@@ -44,6 +61,17 @@ impl PrivateKey {
         Ok(Signature { inner: signature })
     }
 
+    // signature the public key can be recovered from, see PublicKey::recover
+    pub fn sign_recoverable(&self, data: &[u8]) -> Result<RecoverableSignature, EcdsaErr> {
+        let signing_key = SigningKey::from_slice(self.as_bytes())?;
+        let (signature, recovery_id) = signing_key.sign_recoverable(data)?;
+
+        Ok(RecoverableSignature {
+            inner: signature,
+            recovery_id,
+        })
+    }
+
     pub fn as_bytes(&self) -> &[u8; 32] {
         &self.key_bytes
     }
@@ -53,8 +81,16 @@ impl PrivateKey {
     }
 }
 
-#[derive(Debug)]
+// wipe the key from memory once it's not used anymore
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.key_bytes.zeroize();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct PublicKey {
+    #[serde(with = "serde_bytes")]
     key_bytes: Vec<u8>,
 }
 
@@ -72,6 +108,32 @@ impl PublicKey {
         }
     }
 
+    // sec1 encoded point, as pushed by an unlocking script
+    pub fn from_sec1(bytes: &[u8]) -> Result<Self, EcdsaErr> {
+        VerifyingKey::from_sec1_bytes(bytes)?;
+        Ok(Self {
+            key_bytes: bytes.to_vec(),
+        })
+    }
+
+    // Base58Check address of the key hash, what P2PKH outputs lock to
+    pub fn address(&self) -> String {
+        Address::PubKeyHash(self.hash()).to_string()
+    }
+
+    pub fn hash(&self) -> [u8; 20] {
+        hash160(&self.key_bytes)
+    }
+
+    // key that made `signature` of `data`. any signature recovers some key:
+    // it's the signer's only if it's the key expected
+    pub fn recover(data: &[u8], signature: &RecoverableSignature) -> Result<Self, EcdsaErr> {
+        let key = VerifyingKey::recover_from_msg(data, &signature.inner, signature.recovery_id)?;
+        Ok(Self {
+            key_bytes: key.to_encoded_point(true).as_bytes().to_vec(),
+        })
+    }
+
     pub fn verify(&self, signature: &Signature, data: &[u8]) -> Result<(), EcdsaErr> {
         let verifying_key = VerifyingKey::from_sec1_bytes(&self.key_bytes);
         if let Err(e) = verifying_key {
@@ -90,7 +152,7 @@ impl PublicKey {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Signature {
     inner: EcdsaSignature,
 }
@@ -138,6 +200,10 @@ impl<'de> serde::Deserialize<'de> for Signature {
 }
 
 impl Signature {
+    pub fn from_der(bytes: &[u8]) -> Result<Self, EcdsaErr> {
+        EcdsaSignature::from_der(bytes).map(|sig| Signature { inner: sig })
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         self.inner.to_der().to_bytes().to_vec()
     }
@@ -147,6 +213,45 @@ impl Signature {
     }
 }
 
+// compact signature: r ++ s (64 bytes) ++ recovery id (1 byte), 65 bytes against 70 to 72
+// for DER, and the public key doesn't have to be sent along
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoverableSignature {
+    inner: EcdsaSignature,
+    recovery_id: RecoveryId,
+}
+
+impl RecoverableSignature {
+    pub const SIZE: usize = 65;
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EcdsaErr> {
+        if bytes.len() != Self::SIZE {
+            return Err(EcdsaErr::new());
+        }
+        Ok(Self {
+            inner: EcdsaSignature::from_slice(&bytes[..64])?,
+            recovery_id: RecoveryId::from_byte(bytes[64]).ok_or_else(EcdsaErr::new)?,
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.inner.to_bytes().to_vec();
+        bytes.push(self.recovery_id.to_byte());
+        bytes
+    }
+
+    // the plain signature, the recovered key verifies it
+    pub fn signature(&self) -> Signature {
+        Signature { inner: self.inner }
+    }
+}
+
+// ripemd160(sha256(data))
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    let sha = hex::decode(sha256::digest(data)).unwrap();
+    Ripemd160::digest(sha).into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -182,28 +287,31 @@ mod tests {
     }
 
     #[test]
-    fn poc_recv_key() {
+    fn recover_key() {
         let b = Block::new("prev_hash".into(), 0, 0, vec![]);
         let encoded_b = &b.encode().unwrap();
 
         let private_key = PrivateKey::generate().unwrap();
-        let signature = private_key.sign(&encoded_b).unwrap();
-
-        let recid = RecoveryId::try_from(1u8); // 0, 1 work
-        let recovered_key = VerifyingKey::recover_from_msg(
-            &encoded_b.as_slice()[..],
-            &signature.inner,
-            recid.unwrap(),
+        let signature = private_key.sign_recoverable(encoded_b).unwrap();
+        let bytes = signature.as_bytes();
+        assert_eq!(bytes.len(), RecoverableSignature::SIZE);
+        let signature = RecoverableSignature::from_bytes(&bytes).unwrap();
+
+        let recovered = PublicKey::recover(encoded_b, &signature).unwrap();
+        assert_eq!(recovered, private_key.public_key());
+        assert!(recovered.verify(&signature.signature(), encoded_b).is_ok());
+
+        // other data or the other recovery id: some other key
+        let other = PublicKey::recover(b"other data", &signature);
+        assert_ne!(other.ok(), Some(private_key.public_key()));
+        let mut flipped = bytes.clone();
+        flipped[64] ^= 1;
+        let flipped = RecoverableSignature::from_bytes(&flipped).unwrap();
+        assert_ne!(
+            PublicKey::recover(encoded_b, &flipped).ok(),
+            Some(private_key.public_key())
         );
-        if let Err(ref e) = recovered_key {
-            dbg!(&e);
-            assert!(false)
-        }
-
-        let valid = recovered_key
-            .unwrap()
-            .verify(&encoded_b[..], &signature.inner);
-        assert!(valid.is_ok());
+        assert!(RecoverableSignature::from_bytes(&bytes[..64]).is_err());
     }
 
     #[test]
@@ -0,0 +1,453 @@
+use super::cyphers::{hash160, PrivateKey, PublicKey};
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::elliptic_curve::PrimeField;
+use k256::{ProjectivePoint, Scalar};
+use sha2::Sha512;
+use std::fmt;
+use std::str::FromStr;
+use zeroize::Zeroize;
+
+// BIP32 hierarchical deterministic keys
+// - the master key and chain code come from a seed: hmac-sha512("Bitcoin seed", seed)
+// - a child is derived from its parent key, chain code and index: hmac-sha512(chain code,
+//   parent public key ++ index), the left half tweaks the parent key, the right half is
+//   the child's chain code
+// - hardened children (index >= 2^31) hash the parent private key instead: they can't be
+//   derived from an extended public key
+// - extended keys encode as the Base58Check of their 78 bytes BIP32 serialization, xprv.../xpub...
+//   version(4) ++ depth(1) ++ parent fingerprint(4) ++ child number(4, BE) ++ chain code(32)
+//   ++ key(33: 0x00 ++ private key, or the compressed public key)
+
+pub const HARDENED: u32 = 1 << 31;
+
+const SEED_KEY: &[u8] = b"Bitcoin seed";
+const XPRV_VERSION: [u8; 4] = [0x04, 0x88, 0xad, 0xe4];
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const ENCODED_SIZE: usize = 78;
+
+#[derive(Debug, PartialEq)]
+pub enum HdErr {
+    InvalidSeed,        // not 16 to 64 bytes, or it makes an invalid master key
+    InvalidChild(u32),  // the index makes an invalid key (~1 in 2^127), use the next one
+    HardenedChild(u32), // from an extended public key
+    InvalidPath(String),
+    InvalidEncoding,
+}
+
+// path from the master key: m/44'/0'/0'/0/1, ' (or h) marking hardened indexes
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = HdErr;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = || HdErr::InvalidPath(path.to_string());
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(invalid());
+        }
+        let indexes = parts
+            .map(|part| {
+                let (index, hardened) = match part.strip_suffix(['\'', 'h']) {
+                    Some(index) => (index, HARDENED),
+                    None => (part, 0),
+                };
+                match index.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index | hardened),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<Vec<u32>, HdErr>>()?;
+        Ok(DerivationPath(indexes))
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap(); // any key size
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+// left half of a derivation hash as a key tweak, None when it's not below the curve order
+fn tweak(hash: &[u8; 64]) -> Option<Scalar> {
+    let bytes: [u8; 32] = hash[..32].try_into().unwrap();
+    Option::from(Scalar::from_repr(bytes.into()))
+}
+
+fn compressed(public_key: &PublicKey) -> Vec<u8> {
+    k256::PublicKey::from_sec1_bytes(&public_key.as_bytes())
+        .unwrap() // valid point, checked when the key was made
+        .to_encoded_point(true)
+        .as_bytes()
+        .to_vec()
+}
+
+#[derive(Clone)]
+pub struct ExtendedPrivateKey {
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    chain_code: [u8; 32],
+    private_key: PrivateKey,
+}
+
+impl ExtendedPrivateKey {
+    pub fn from_seed(seed: &[u8]) -> Result<Self, HdErr> {
+        if !(16..=64).contains(&seed.len()) {
+            return Err(HdErr::InvalidSeed);
+        }
+        let mut hash = hmac_sha512(SEED_KEY, seed);
+        let key_bytes: [u8; 32] = hash[..32].try_into().unwrap();
+        let private_key = PrivateKey::from_bytes(key_bytes).map_err(|_| HdErr::InvalidSeed);
+        let chain_code = hash[32..].try_into().unwrap();
+        hash.zeroize();
+        Ok(Self {
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+            chain_code,
+            private_key: private_key?,
+        })
+    }
+
+    pub fn private_key(&self) -> &PrivateKey {
+        &self.private_key
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.private_key.public_key()
+    }
+
+    // the extended public key, it derives the same non hardened public keys
+    pub fn to_public(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+            chain_code: self.chain_code,
+            public_key: self.public_key(),
+        }
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, HdErr> {
+        let mut data = if index >= HARDENED {
+            [&[0u8][..], self.private_key.as_bytes()].concat()
+        } else {
+            compressed(&self.public_key())
+        };
+        data.extend(index.to_be_bytes());
+        let mut hash = hmac_sha512(&self.chain_code, &data);
+        data.zeroize();
+
+        let parent: [u8; 32] = *self.private_key.as_bytes();
+        let child = tweak(&hash).and_then(|tweak| {
+            let parent = Option::<Scalar>::from(Scalar::from_repr(parent.into()))?;
+            let child: [u8; 32] = (tweak + parent).to_bytes().into();
+            PrivateKey::from_bytes(child).ok() // zero key
+        });
+        let chain_code = hash[32..].try_into().unwrap();
+        hash.zeroize();
+
+        Ok(Self {
+            depth: self
+                .depth
+                .checked_add(1)
+                .ok_or(HdErr::InvalidChild(index))?,
+            parent_fingerprint: self.to_public().fingerprint(),
+            child_number: index,
+            chain_code,
+            private_key: child.ok_or(HdErr::InvalidChild(index))?,
+        })
+    }
+
+    pub fn derive_path(&self, path: &DerivationPath) -> Result<Self, HdErr> {
+        let Some((first, rest)) = path.0.split_first() else {
+            return Ok(self.clone());
+        };
+        rest.iter()
+            .try_fold(self.derive_child(*first)?, |key, index| {
+                key.derive_child(*index)
+            })
+    }
+
+    pub fn encode(&self) -> String {
+        let mut key = [&[0u8][..], self.private_key.as_bytes()].concat();
+        let header = (self.depth, self.parent_fingerprint, self.child_number);
+        let encoded = encode(XPRV_VERSION, header, &self.chain_code, &key);
+        key.zeroize();
+        encoded
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, HdErr> {
+        let (header, chain_code, key) = decode(encoded, XPRV_VERSION)?;
+        if key[0] != 0 {
+            return Err(HdErr::InvalidEncoding);
+        }
+        let key_bytes: [u8; 32] = key[1..].try_into().unwrap();
+        let (depth, parent_fingerprint, child_number) = header;
+        Ok(Self {
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            private_key: PrivateKey::from_bytes(key_bytes).map_err(|_| HdErr::InvalidEncoding)?,
+        })
+    }
+}
+
+// neither the key nor the chain code are printed
+impl fmt::Debug for ExtendedPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtendedPrivateKey")
+            .field("depth", &self.depth)
+            .field("parent_fingerprint", &self.parent_fingerprint)
+            .field("child_number", &self.child_number)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for ExtendedPrivateKey {
+    fn drop(&mut self) {
+        self.chain_code.zeroize();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedPublicKey {
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    chain_code: [u8; 32],
+    public_key: PublicKey,
+}
+
+impl ExtendedPublicKey {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    // first 4 bytes of the key hash, how children refer to their parent
+    pub fn fingerprint(&self) -> [u8; 4] {
+        hash160(&compressed(&self.public_key))[..4]
+            .try_into()
+            .unwrap()
+    }
+
+    // non hardened child, the public key of the private child at `index`
+    pub fn derive_child(&self, index: u32) -> Result<Self, HdErr> {
+        if index >= HARDENED {
+            return Err(HdErr::HardenedChild(index));
+        }
+        let mut data = compressed(&self.public_key);
+        data.extend(index.to_be_bytes());
+        let hash = hmac_sha512(&self.chain_code, &data);
+
+        let parent = k256::PublicKey::from_sec1_bytes(&self.public_key.as_bytes())
+            .unwrap()
+            .to_projective();
+        let child = tweak(&hash)
+            .map(|tweak| ProjectivePoint::GENERATOR * tweak + parent)
+            .and_then(|point| k256::PublicKey::from_affine(point.to_affine()).ok()) // identity
+            .ok_or(HdErr::InvalidChild(index))?;
+        let public_key = PublicKey::from_sec1(child.to_encoded_point(true).as_bytes())
+            .map_err(|_| HdErr::InvalidChild(index))?;
+
+        Ok(Self {
+            depth: self
+                .depth
+                .checked_add(1)
+                .ok_or(HdErr::InvalidChild(index))?,
+            parent_fingerprint: self.fingerprint(),
+            child_number: index,
+            chain_code: hash[32..].try_into().unwrap(),
+            public_key,
+        })
+    }
+
+    // relative to this key, no hardened index
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self, HdErr> {
+        let mut key = self.clone();
+        for index in path {
+            key = key.derive_child(*index)?;
+        }
+        Ok(key)
+    }
+
+    pub fn encode(&self) -> String {
+        let key = compressed(&self.public_key);
+        let header = (self.depth, self.parent_fingerprint, self.child_number);
+        encode(XPUB_VERSION, header, &self.chain_code, &key)
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, HdErr> {
+        let (header, chain_code, key) = decode(encoded, XPUB_VERSION)?;
+        let (depth, parent_fingerprint, child_number) = header;
+        Ok(Self {
+            depth,
+            parent_fingerprint,
+            child_number,
+            chain_code,
+            public_key: PublicKey::from_sec1(&key).map_err(|_| HdErr::InvalidEncoding)?,
+        })
+    }
+}
+
+fn encode(version: [u8; 4], header: (u8, [u8; 4], u32), chain_code: &[u8], key: &[u8]) -> String {
+    let (depth, parent_fingerprint, child_number) = header;
+    let mut bytes = Vec::with_capacity(ENCODED_SIZE);
+    bytes.extend(version);
+    bytes.push(depth);
+    bytes.extend(parent_fingerprint);
+    bytes.extend(child_number.to_be_bytes());
+    bytes.extend(chain_code);
+    bytes.extend(key);
+    let encoded = bs58::encode(&bytes).with_check().into_string();
+    bytes.zeroize();
+    encoded
+}
+
+type Decoded = ((u8, [u8; 4], u32), [u8; 32], Vec<u8>);
+
+fn decode(encoded: &str, version: [u8; 4]) -> Result<Decoded, HdErr> {
+    // a mistyped key fails the checksum
+    let bytes = bs58::decode(encoded)
+        .with_check(None)
+        .into_vec()
+        .map_err(|_| HdErr::InvalidEncoding)?;
+    if bytes.len() != ENCODED_SIZE || bytes[..4] != version {
+        return Err(HdErr::InvalidEncoding);
+    }
+    let header = (
+        bytes[4],
+        bytes[5..9].try_into().unwrap(),
+        u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+    );
+    Ok((
+        header,
+        bytes[13..45].try_into().unwrap(),
+        bytes[45..].to_vec(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BIP32 test vector 1
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f";
+
+    #[test]
+    fn derivation() {
+        let master = ExtendedPrivateKey::from_seed(&hex::decode(SEED).unwrap()).unwrap();
+        assert_eq!(
+            master.private_key().as_hex(),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508"
+        );
+
+        let expected = [
+            (
+                "m/0'",
+                "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea",
+            ),
+            (
+                "m/0'/1",
+                "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368",
+            ),
+            (
+                "m/0h/1/2h/2/1000000000",
+                "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8",
+            ),
+        ];
+        for (path, key) in expected {
+            let child = master.derive_path(&path.parse().unwrap()).unwrap();
+            assert_eq!(child.private_key().as_hex(), key, "{}", path);
+        }
+        let child = master.derive_path(&"m/0'/1".parse().unwrap()).unwrap();
+        assert_eq!(child.depth, 2);
+        assert_eq!(child.child_number, 1);
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19"
+        );
+        assert_eq!(
+            hex::encode(child.parent_fingerprint),
+            "5c1bd648" // fingerprint of m/0'
+        );
+    }
+
+    #[test]
+    fn public_derivation() {
+        let master = ExtendedPrivateKey::from_seed(&hex::decode(SEED).unwrap()).unwrap();
+        let account = master.derive_path(&"m/0'".parse().unwrap()).unwrap();
+        let xpub = account.to_public();
+
+        // the same public keys, without the private ones
+        let private_child = account.derive_path(&"m/1/2".parse().unwrap()).unwrap();
+        let public_child = xpub.derive_path(&[1, 2]).unwrap();
+        assert_eq!(public_child, private_child.to_public());
+        assert_eq!(
+            xpub.derive_child(HARDENED),
+            Err(HdErr::HardenedChild(HARDENED))
+        );
+
+        // BIP32 test vector 1 encodings, and round trip
+        assert_eq!(
+            master.to_public().encode(),
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8"
+        );
+        assert_eq!(
+            master.encode(),
+            "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi"
+        );
+        assert_eq!(
+            xpub.encode(),
+            "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw"
+        );
+        let decoded = ExtendedPublicKey::decode(&xpub.encode()).unwrap();
+        assert_eq!(decoded, xpub);
+        let decoded = ExtendedPrivateKey::decode(&account.encode()).unwrap();
+        assert_eq!(decoded.to_public(), xpub);
+        assert_eq!(
+            ExtendedPrivateKey::decode(&xpub.encode()).unwrap_err(),
+            HdErr::InvalidEncoding
+        );
+
+        // one character off: the checksum doesn't match
+        let encoded = xpub.encode();
+        let last = if encoded.ends_with('w') { "x" } else { "w" };
+        let typo = format!("{}{}", &encoded[..encoded.len() - 1], last);
+        assert_eq!(
+            ExtendedPublicKey::decode(&typo).unwrap_err(),
+            HdErr::InvalidEncoding
+        );
+        assert_eq!(
+            ExtendedPublicKey::decode(&hex::encode(b"not base58")).unwrap_err(),
+            HdErr::InvalidEncoding
+        );
+    }
+
+    #[test]
+    fn parse_path() {
+        assert_eq!(
+            "m/44'/0h/7".parse(),
+            Ok(DerivationPath(vec![44 | HARDENED, HARDENED, 7]))
+        );
+        assert_eq!("m".parse(), Ok(DerivationPath(vec![])));
+        for invalid in ["", "44'/0", "m/", "m/x", "m/2147483648"] {
+            assert_eq!(
+                invalid.parse::<DerivationPath>(),
+                Err(HdErr::InvalidPath(invalid.into()))
+            );
+        }
+        assert_eq!(
+            ExtendedPrivateKey::from_seed(&[0; 8]).unwrap_err(),
+            HdErr::InvalidSeed
+        );
+    }
+}
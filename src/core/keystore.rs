@@ -0,0 +1,411 @@
+use super::cyphers::{PrivateKey, PublicKey};
+use super::hd::ExtendedPrivateKey;
+use super::mnemonic::Mnemonic;
+use super::wallet::first_receive_key;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use k256::elliptic_curve::rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+// Wallet key file
+// - the secret (a private key, or the account key and seed phrase of an HD wallet) is
+//   encrypted with ChaCha20-Poly1305 under a key derived from the passphrase with scrypt
+//   (memory-hard), a fresh salt and nonce every time it's written
+// - the public key (of the first receive key for an HD wallet) is kept in clear so a locked
+//   wallet still knows its address, it's authenticated with the ciphertext: editing it makes
+//   decryption fail
+// - version 1 files hold a bare 32 bytes private key, they're still read
+// - the file is created readable by its owner only, never over an existing one (it may hold
+//   the only copy of another key), and replaced atomically (write tmp -> rename)
+// - the scrypt cost stored in a file isn't trusted: anything but KDF_PARAMS is rejected, a
+//   tampered file could otherwise make unlocking allocate any amount of memory
+
+const VERSION: u8 = 2;
+const KEY_ONLY_VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+
+// scrypt cost: 2^15 * 8 * 128 bytes = 32 MiB of memory per derivation
+const KDF_PARAMS: KdfParams = KdfParams {
+    log_n: 15,
+    r: 8,
+    p: 1,
+};
+
+#[derive(Debug)]
+pub enum KeystoreErr {
+    Io(std::io::Error),
+    Encode(String),
+    UnsupportedVersion(u8),
+    UnsupportedKdf,  // scrypt cost other than KDF_PARAMS
+    WrongPassphrase, // or a tampered file
+    InvalidKey,
+    NotPersisted,  // in memory wallet, it has no keystore to lock to
+    AlreadyExists, // a keystore is already saved there, it's left untouched
+    Locked,        // the wallet has no key to save while locked
+}
+
+impl From<std::io::Error> for KeystoreErr {
+    fn from(e: std::io::Error) -> Self {
+        KeystoreErr::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl KdfParams {
+    fn derive_key(
+        &self,
+        passphrase: &str,
+        salt: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, KeystoreErr> {
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, 32)
+            .map_err(|e| KeystoreErr::Encode(e.to_string()))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(passphrase.as_bytes(), salt, &params, key.as_mut())
+            .map_err(|e| KeystoreErr::Encode(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+// what a keystore encrypts
+#[derive(Debug, Clone)]
+pub enum Secret {
+    Key(PrivateKey),
+    Account {
+        account: ExtendedPrivateKey,
+        mnemonic: Option<Mnemonic>, // to back the wallet up again
+    },
+}
+
+impl Secret {
+    // the key of the wallet's address
+    fn public_key(&self) -> Result<PublicKey, KeystoreErr> {
+        match self {
+            Secret::Key(private_key) => Ok(private_key.public_key()),
+            Secret::Account { account, .. } => first_receive_key(account)
+                .map(|key| key.public_key())
+                .map_err(|_| KeystoreErr::InvalidKey),
+        }
+    }
+}
+
+// the encrypted bytes, an encoded Secret
+#[derive(Serialize, Deserialize)]
+enum Plaintext {
+    Key([u8; 32]),
+    Account {
+        xprv: String,
+        phrase: Option<String>,
+    },
+}
+
+impl Drop for Plaintext {
+    fn drop(&mut self) {
+        match self {
+            Plaintext::Key(key_bytes) => key_bytes.zeroize(),
+            Plaintext::Account { xprv, phrase } => {
+                xprv.zeroize();
+                phrase.zeroize();
+            }
+        }
+    }
+}
+
+impl Plaintext {
+    fn from_secret(secret: &Secret) -> Self {
+        match secret {
+            Secret::Key(private_key) => Plaintext::Key(*private_key.as_bytes()),
+            Secret::Account { account, mnemonic } => Plaintext::Account {
+                xprv: account.encode(),
+                phrase: mnemonic
+                    .as_ref()
+                    .map(|mnemonic| mnemonic.phrase().as_str().to_owned()),
+            },
+        }
+    }
+
+    fn to_secret(&self) -> Result<Secret, KeystoreErr> {
+        match self {
+            Plaintext::Key(key_bytes) => PrivateKey::from_bytes(*key_bytes)
+                .map(Secret::Key)
+                .map_err(|_| KeystoreErr::InvalidKey),
+            Plaintext::Account { xprv, phrase } => Ok(Secret::Account {
+                account: ExtendedPrivateKey::decode(xprv).map_err(|_| KeystoreErr::InvalidKey)?,
+                mnemonic: match phrase {
+                    Some(phrase) => {
+                        Some(Mnemonic::parse(phrase).map_err(|_| KeystoreErr::InvalidKey)?)
+                    }
+                    None => None,
+                },
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Keystore {
+    version: u8,
+    public_key: PublicKey,
+    kdf: KdfParams,
+    salt: [u8; SALT_SIZE],
+    nonce: [u8; 12],
+    #[serde(with = "serde_bytes")]
+    ciphertext: Vec<u8>, // encoded Plaintext + auth tag
+}
+
+impl Keystore {
+    // encrypt `secret` under `passphrase`
+    pub fn encrypt(secret: &Secret, passphrase: &str) -> Result<Self, KeystoreErr> {
+        let kdf = KDF_PARAMS;
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let key = kdf.derive_key(passphrase, &salt)?;
+
+        let public_key = secret.public_key()?;
+        let plaintext = Zeroizing::new(
+            bincode::serialize(&Plaintext::from_secret(secret))
+                .map_err(|e| KeystoreErr::Encode(e.to_string()))?,
+        );
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &public_key.as_bytes(),
+        };
+        let ciphertext = ChaCha20Poly1305::new(key.as_ref().into())
+            .encrypt(&nonce, payload)
+            .map_err(|_| KeystoreErr::Encode("encryption failed".into()))?;
+
+        Ok(Self {
+            version: VERSION,
+            public_key,
+            kdf,
+            salt,
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    // the secret, if `passphrase` is the one it was encrypted under
+    pub fn decrypt(&self, passphrase: &str) -> Result<Secret, KeystoreErr> {
+        let key = self.kdf.derive_key(passphrase, &self.salt)?;
+        let payload = Payload {
+            msg: &self.ciphertext,
+            aad: &self.public_key.as_bytes(),
+        };
+        let plaintext = Zeroizing::new(
+            ChaCha20Poly1305::new(key.as_ref().into())
+                .decrypt(&self.nonce.into(), payload)
+                .map_err(|_| KeystoreErr::WrongPassphrase)?,
+        );
+
+        let secret = if self.version == KEY_ONLY_VERSION {
+            let key_bytes: Zeroizing<[u8; 32]> = Zeroizing::new(
+                plaintext
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| KeystoreErr::InvalidKey)?,
+            );
+            Plaintext::Key(*key_bytes).to_secret()?
+        } else {
+            bincode::deserialize::<Plaintext>(&plaintext)
+                .map_err(|_| KeystoreErr::InvalidKey)?
+                .to_secret()?
+        };
+        if secret.public_key()? != self.public_key {
+            return Err(KeystoreErr::InvalidKey);
+        }
+        Ok(secret)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, KeystoreErr> {
+        let bytes = fs::read(path)?;
+        let keystore: Keystore =
+            bincode::deserialize(&bytes).map_err(|e| KeystoreErr::Encode(e.to_string()))?;
+        if keystore.version != VERSION && keystore.version != KEY_ONLY_VERSION {
+            return Err(KeystoreErr::UnsupportedVersion(keystore.version));
+        }
+        if keystore.kdf != KDF_PARAMS {
+            return Err(KeystoreErr::UnsupportedKdf);
+        }
+        Ok(keystore)
+    }
+
+    // save to a new file at `path`, failing if there's one already
+    pub fn create<P: AsRef<Path>>(&self, path: P) -> Result<(), KeystoreErr> {
+        let mut file = create_private(path.as_ref()).map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => KeystoreErr::AlreadyExists,
+            _ => KeystoreErr::Io(e),
+        })?;
+        self.write_to(&mut file)?;
+        sync_parent(path.as_ref())?;
+        Ok(())
+    }
+
+    // replace the keystore saved at `path`
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), KeystoreErr> {
+        let path = path.as_ref();
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        match fs::remove_file(&tmp_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {} // left by a crash, or none
+        }
+        self.write_to(&mut create_private(tmp_path.as_ref())?)?;
+        fs::rename(&tmp_path, path)?;
+        sync_parent(path)?;
+        Ok(())
+    }
+
+    fn write_to(&self, file: &mut File) -> Result<(), KeystoreErr> {
+        let encoded = bincode::serialize(self).map_err(|e| KeystoreErr::Encode(e.to_string()))?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+// new file only its owner can read and write
+fn create_private(path: &Path) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+// the directory entry of a file created or renamed at `path` is durable once this returns
+fn sync_parent(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let private_key = PrivateKey::generate().unwrap();
+        let secret = Secret::Key(private_key.clone());
+        let keystore = Keystore::encrypt(&secret, "passphrase").unwrap();
+        assert_eq!(keystore.public_key(), &private_key.public_key());
+
+        let Ok(Secret::Key(decrypted)) = keystore.decrypt("passphrase") else {
+            panic!("expected the private key");
+        };
+        assert_eq!(decrypted.as_bytes(), private_key.as_bytes());
+        assert!(matches!(
+            keystore.decrypt("wrong"),
+            Err(KeystoreErr::WrongPassphrase)
+        ));
+
+        // the public key is authenticated with the encrypted key
+        let mut swapped = keystore.clone();
+        swapped.public_key = PrivateKey::generate().unwrap().public_key();
+        assert!(matches!(
+            swapped.decrypt("passphrase"),
+            Err(KeystoreErr::WrongPassphrase)
+        ));
+        let mut flipped = keystore.clone();
+        flipped.ciphertext[0] ^= 1;
+        assert!(matches!(
+            flipped.decrypt("passphrase"),
+            Err(KeystoreErr::WrongPassphrase)
+        ));
+
+        // same key and passphrase, another salt and nonce
+        let again = Keystore::encrypt(&secret, "passphrase").unwrap();
+        assert_ne!(again.salt, keystore.salt);
+        assert_ne!(again.ciphertext, keystore.ciphertext);
+    }
+
+    #[test]
+    fn hd_account_and_key_only_files() {
+        let mnemonic = Mnemonic::generate(12).unwrap();
+        let account = ExtendedPrivateKey::from_seed(mnemonic.to_seed("").as_ref()).unwrap();
+        let secret = Secret::Account {
+            account: account.clone(),
+            mnemonic: Some(mnemonic.clone()),
+        };
+        let keystore = Keystore::encrypt(&secret, "passphrase").unwrap();
+        let first = first_receive_key(&account).unwrap();
+        assert_eq!(keystore.public_key(), &first.public_key());
+        let Ok(Secret::Account {
+            account: decrypted,
+            mnemonic: Some(phrase),
+        }) = keystore.decrypt("passphrase")
+        else {
+            panic!("expected the account key and seed phrase");
+        };
+        assert_eq!(decrypted.to_public(), account.to_public());
+        assert_eq!(phrase, mnemonic);
+
+        // written by version 1: the bare private key
+        let kdf = keystore.kdf;
+        let key = kdf.derive_key("passphrase", &keystore.salt).unwrap();
+        let payload = Payload {
+            msg: first.as_bytes(),
+            aad: &keystore.public_key.as_bytes(),
+        };
+        let old = Keystore {
+            version: KEY_ONLY_VERSION,
+            ciphertext: ChaCha20Poly1305::new(key.as_ref().into())
+                .encrypt(&keystore.nonce.into(), payload)
+                .unwrap(),
+            ..keystore
+        };
+        let Ok(Secret::Key(decrypted)) = old.decrypt("passphrase") else {
+            panic!("expected the private key");
+        };
+        assert_eq!(decrypted.as_bytes(), first.as_bytes());
+    }
+
+    #[test]
+    fn tampered_kdf_is_rejected() {
+        let dir = crate::core::storage::temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.key");
+        let secret = Secret::Key(PrivateKey::generate().unwrap());
+        let keystore = Keystore::encrypt(&secret, "passphrase").unwrap();
+        keystore.create(&path).unwrap();
+        assert!(Keystore::read(&path).is_ok());
+
+        // 2^40 * 8 * 128 bytes to derive the key
+        let costly = Keystore {
+            kdf: KdfParams {
+                log_n: 40,
+                ..KDF_PARAMS
+            },
+            ..keystore
+        };
+        costly.write(&path).unwrap();
+        assert!(matches!(
+            Keystore::read(&path),
+            Err(KeystoreErr::UnsupportedKdf)
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
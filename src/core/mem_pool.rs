@@ -0,0 +1,429 @@
+use super::amount::Amount;
+use super::transaction::{OutPoint, Transaction, TxValidationErr};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+// Pending trxs, kept in arrival order
+// - fee rate: fee paid per encoded byte of the trx
+// - block templates take the highest fee rates first, as long as they fit
+// - over `max_size` (encoded bytes of all entries) the lowest fee rates are evicted,
+//   newest first among equal rates
+// - replace by fee: a trx spending an input of opted in pending trxs replaces them, when it
+//   pays more than all of them and a higher fee rate than each. pending trxs only spend
+//   confirmed outputs (see BlockChain::add_transaction), none has pending descendants
+// - the outpoints pending trxs spend are indexed: checking a new trx against the pool
+//   doesn't decode every entry
+
+#[derive(Debug, Clone)]
+pub struct MemPoolEntry {
+    pub tx: Transaction,
+    pub fee: Amount,
+    pub size: usize,          // encoded bytes
+    prev_outs: Vec<OutPoint>, // what it spends
+}
+
+impl MemPoolEntry {
+    pub fn new(tx: Transaction, fee: Amount) -> Self {
+        let size = tx.size();
+        let prev_outs = tx
+            .tx_data()
+            .map(|data| {
+                data.inputs
+                    .into_iter()
+                    .map(|input| input.prev_out)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            tx,
+            fee,
+            size,
+            prev_outs,
+        }
+    }
+
+    // fee / size against other.fee / other.size, without rounding
+    pub fn cmp_fee_rate(&self, other: &MemPoolEntry) -> Ordering {
+        let lhs = self.fee.units() as u128 * other.size as u128;
+        let rhs = other.fee.units() as u128 * self.size as u128;
+        lhs.cmp(&rhs)
+    }
+}
+
+// highest fee rate first, earlier arrival first among equal rates
+fn by_fee_rate(a: &(usize, &MemPoolEntry), b: &(usize, &MemPoolEntry)) -> Ordering {
+    b.1.cmp_fee_rate(a.1).then(a.0.cmp(&b.0))
+}
+
+#[derive(Debug)]
+pub struct MemPool {
+    entries: Vec<MemPoolEntry>,
+    spent: HashMap<OutPoint, String>, // trx_id of the entry spending each outpoint
+    size: usize,
+    max_size: usize,
+}
+
+impl MemPool {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: vec![],
+            spent: HashMap::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // encoded bytes of all pending trxs
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, tx: &Transaction) -> bool {
+        self.entries.iter().any(|entry| &entry.tx == tx)
+    }
+
+    pub fn get(&self, trx_id: &str) -> Option<&MemPoolEntry> {
+        self.entries.iter().find(|entry| entry.tx.trx_id == trx_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MemPoolEntry> {
+        self.entries.iter()
+    }
+
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.entries.iter().map(|entry| entry.tx.clone()).collect()
+    }
+
+    // id of the pending trx spending `outpoint`
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<&str> {
+        self.spent.get(outpoint).map(String::as_str)
+    }
+
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spent.contains_key(outpoint)
+    }
+
+    // entries and size changed other than by a push: rebuild what's derived from them
+    fn reindex(&mut self) {
+        self.size = self.entries.iter().map(|entry| entry.size).sum();
+        self.spent = self
+            .entries
+            .iter()
+            .flat_map(|entry| {
+                let trx_id = &entry.tx.trx_id;
+                entry
+                    .prev_outs
+                    .iter()
+                    .map(move |prev_out| (prev_out.clone(), trx_id.clone()))
+            })
+            .collect();
+    }
+
+    fn sorted_by_fee_rate(&self) -> Vec<(usize, &MemPoolEntry)> {
+        let mut sorted: Vec<(usize, &MemPoolEntry)> = self.entries.iter().enumerate().collect();
+        sorted.sort_by(by_fee_rate);
+        sorted
+    }
+
+    // add `tx` paying `fee`, then evict the lowest fee rates until the pool fits `max_size` again.
+    // returns the evicted trxs, fails (leaving the pool as is) when `tx` would be one of them.
+    pub fn insert(
+        &mut self,
+        tx: Transaction,
+        fee: Amount,
+    ) -> Result<Vec<Transaction>, TxValidationErr> {
+        let entry = MemPoolEntry::new(tx, fee);
+        self.size += entry.size;
+        for prev_out in &entry.prev_outs {
+            self.spent.insert(prev_out.clone(), entry.tx.trx_id.clone());
+        }
+        self.entries.push(entry);
+
+        let mut size = self.size;
+        let mut victims = vec![];
+        for (index, entry) in self.sorted_by_fee_rate().into_iter().rev() {
+            if size <= self.max_size {
+                break;
+            }
+            size -= entry.size;
+            victims.push(index);
+        }
+
+        if victims.contains(&(self.entries.len() - 1)) {
+            let entry = self.entries.pop().unwrap();
+            self.size -= entry.size;
+            for prev_out in &entry.prev_outs {
+                self.spent.remove(prev_out);
+            }
+            return Err(TxValidationErr::MemPoolFull);
+        }
+        if victims.is_empty() {
+            return Ok(vec![]);
+        }
+        victims.sort_unstable();
+        let evicted = victims
+            .into_iter()
+            .rev()
+            .map(|index| self.entries.remove(index).tx)
+            .collect();
+        self.reindex();
+        Ok(evicted)
+    }
+
+    // the pending trxs `tx` would replace: those spending one of its inputs.
+    // fails when one of those spending its inputs didn't opt in to be replaced.
+    pub fn replaced_by(&self, tx: &Transaction) -> Result<Vec<&MemPoolEntry>, TxValidationErr> {
+        let inputs: HashSet<OutPoint> = tx
+            .tx_data()?
+            .inputs
+            .into_iter()
+            .map(|input| input.prev_out)
+            .collect();
+        let mut replaced: HashSet<&str> = HashSet::new();
+        for (prev_out, trx_id) in inputs
+            .iter()
+            .filter_map(|prev_out| Some((prev_out, self.spent.get(prev_out)?)))
+        {
+            if !self.get(trx_id).unwrap().tx.tx_data()?.replaceable {
+                return Err(TxValidationErr::DoubleSpend(prev_out.clone()));
+            }
+            replaced.insert(trx_id);
+        }
+        Ok(self
+            .entries
+            .iter()
+            .filter(|entry| replaced.contains(entry.tx.trx_id.as_str()))
+            .collect())
+    }
+
+    // add `tx` paying `fee` in place of the pending trxs it replaces (see `replaced_by`),
+    // then evict as `insert` does. returns the replaced trxs and the evicted ones.
+    // fails, leaving the pool as is, when it doesn't pay enough to replace them.
+    pub fn replace(
+        &mut self,
+        tx: Transaction,
+        fee: Amount,
+    ) -> Result<(Vec<Transaction>, Vec<Transaction>), TxValidationErr> {
+        let replacement = MemPoolEntry::new(tx, fee);
+        let replaced: Vec<MemPoolEntry> = self
+            .replaced_by(&replacement.tx)?
+            .into_iter()
+            .cloned()
+            .collect();
+        let replaced_fees = Amount::checked_sum(replaced.iter().map(|entry| entry.fee))
+            .ok_or(TxValidationErr::ValueOverflow)?;
+        let higher_rate = replaced
+            .iter()
+            .all(|entry| replacement.cmp_fee_rate(entry) == Ordering::Greater);
+        if !replaced.is_empty() && (fee <= replaced_fees || !higher_rate) {
+            return Err(TxValidationErr::ReplacementFeeTooLow);
+        }
+
+        let replaced: Vec<Transaction> = replaced.into_iter().map(|entry| entry.tx).collect();
+        if replaced.is_empty() {
+            return Ok((replaced, self.insert(replacement.tx, fee)?));
+        }
+        let entries = self.entries.clone();
+        self.remove_included(&replaced);
+        match self.insert(replacement.tx, fee) {
+            Ok(evicted) => Ok((replaced, evicted)),
+            Err(err) => {
+                self.entries = entries;
+                self.reindex();
+                Err(err)
+            }
+        }
+    }
+
+    // drop the pending trxs included in `transactions`
+    pub fn remove_included(&mut self, transactions: &[Transaction]) {
+        let len = self.entries.len();
+        self.entries
+            .retain(|entry| !transactions.contains(&entry.tx));
+        if self.entries.len() != len {
+            self.reindex();
+        }
+    }
+
+    // empty the pool, returns its trxs by fee rate
+    pub fn take(&mut self) -> Vec<MemPoolEntry> {
+        let order: Vec<usize> = self
+            .sorted_by_fee_rate()
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        let mut entries: Vec<Option<MemPoolEntry>> = std::mem::take(&mut self.entries)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.size = 0;
+        self.spent.clear();
+        order
+            .into_iter()
+            .map(|index| entries[index].take().unwrap())
+            .collect()
+    }
+
+    // highest fee rates first, up to `max_size` encoded bytes.
+    // trxs that don't fit are skipped so smaller ones behind them can still get in.
+    pub fn block_template(&self, max_size: usize) -> Vec<Transaction> {
+        let mut size = 0;
+        let mut transactions = vec![];
+        for (_, entry) in self.sorted_by_fee_rate() {
+            if size + entry.size > max_size {
+                continue;
+            }
+            size += entry.size;
+            transactions.push(entry.tx.clone());
+        }
+        transactions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::script::Script;
+    use crate::core::transaction::TxBuilder;
+
+    fn tx(prev_tx_id: &str) -> Transaction {
+        spending(prev_tx_id, 1, false)
+    }
+
+    fn spending(prev_tx_id: &str, value: u64, replaceable: bool) -> Transaction {
+        let prev_out = OutPoint {
+            tx_id: prev_tx_id.into(),
+            index: 0,
+        };
+        let mut builder = TxBuilder::new()
+            .input(prev_out)
+            .output_script(Script::default(), Amount::from_units(value));
+        if replaceable {
+            builder = builder.replaceable();
+        }
+        builder.build().unwrap()
+    }
+
+    const LOW: Amount = Amount::from_units(10);
+    const MID: Amount = Amount::from_units(20);
+    const HIGH: Amount = Amount::from_units(30);
+
+    #[test]
+    fn template_by_fee_rate() {
+        let (low, high, mid) = (tx("a"), tx("b"), tx("c"));
+        let size = low.size();
+        let mut pool = MemPool::new(usize::MAX);
+        for (tx, fee) in [(&low, LOW), (&high, HIGH), (&mid, MID)] {
+            assert!(pool.insert(tx.clone(), fee).unwrap().is_empty());
+        }
+        assert_eq!(pool.size(), 3 * size);
+        assert_eq!(
+            pool.spender(&OutPoint {
+                tx_id: "b".into(),
+                index: 0
+            }),
+            Some(high.trx_id.as_str())
+        );
+
+        assert_eq!(
+            pool.block_template(usize::MAX),
+            vec![high.clone(), mid.clone(), low.clone()]
+        );
+        // the rest is left pending
+        assert_eq!(
+            pool.block_template(2 * size + 1),
+            vec![high.clone(), mid.clone()]
+        );
+        assert!(pool.block_template(size - 1).is_empty());
+
+        pool.remove_included(&[high]);
+        assert_eq!(pool.transactions(), vec![low.clone(), mid.clone()]);
+        let taken: Vec<Transaction> = pool.take().into_iter().map(|entry| entry.tx).collect();
+        assert_eq!(taken, vec![mid, low]);
+        assert!(pool.is_empty());
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn evict_lowest_fee_rate() {
+        let (low, high, mid) = (tx("a"), tx("b"), tx("c"));
+        let mut pool = MemPool::new(2 * low.size());
+        pool.insert(low.clone(), LOW).unwrap();
+        pool.insert(high.clone(), HIGH).unwrap();
+
+        assert_eq!(pool.insert(mid.clone(), MID), Ok(vec![low.clone()]));
+        assert_eq!(pool.transactions(), vec![high.clone(), mid.clone()]);
+
+        // paying less than everything pending
+        assert_eq!(pool.insert(low, LOW), Err(TxValidationErr::MemPoolFull));
+        assert_eq!(pool.transactions(), vec![high, mid]);
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let first = spending("a", 1, true);
+        let second = spending("d", 1, true);
+        let unrelated = tx("b");
+        let locked_in = tx("c");
+        let mut pool = MemPool::new(usize::MAX);
+        for (tx, fee) in [
+            (&first, MID),
+            (&second, LOW),
+            (&unrelated, LOW),
+            (&locked_in, LOW),
+        ] {
+            pool.insert(tx.clone(), fee).unwrap();
+        }
+
+        // didn't opt in
+        let conflict = spending("c", 2, true);
+        assert_eq!(
+            pool.replace(conflict, HIGH),
+            Err(TxValidationErr::DoubleSpend(OutPoint {
+                tx_id: "c".into(),
+                index: 0
+            }))
+        );
+
+        // has to pay more than both trxs it conflicts with together
+        let replacement = TxBuilder::new()
+            .input(OutPoint {
+                tx_id: "a".into(),
+                index: 0,
+            })
+            .input(OutPoint {
+                tx_id: "d".into(),
+                index: 0,
+            })
+            .output_script(Script::default(), Amount::from_units(2))
+            .replaceable()
+            .build()
+            .unwrap();
+        assert_eq!(pool.replaced_by(&replacement).unwrap().len(), 2);
+        let all_fees = MID.checked_add(LOW).unwrap();
+        assert_eq!(
+            pool.replace(replacement.clone(), all_fees),
+            Err(TxValidationErr::ReplacementFeeTooLow)
+        );
+        assert_eq!(pool.len(), 4);
+
+        let fee = all_fees.checked_add(Amount::from_units(1)).unwrap();
+        assert_eq!(
+            pool.replace(replacement.clone(), fee),
+            Ok((vec![first, second], vec![]))
+        );
+        assert_eq!(pool.transactions(), vec![unrelated, locked_in, replacement]);
+        assert_eq!(
+            pool.size(),
+            pool.iter().map(|entry| entry.size).sum::<usize>()
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use super::transaction::Transaction;
+use serde::{Deserialize, Serialize};
+
+// Merkle tree over transaction ids
+// - leaves: sha256(0x00 ++ trx_id), or of the witness id for the witness root
+//   (see Transaction::witness_id)
+// - parent: sha256(0x01 ++ left ++ right), hex strings concatenated. the prefixes keep an
+//   inner node from passing for a leaf: a 128 chars "id" is never taken for two hashes
+// - odd level: the last node moves up as is. pairing it with itself, as bitcoin does, would
+//   give [a, b, c] and [a, b, c, c] the same root
+// - empty tree: EMPTY_MERKLE_ROOT
+
+pub const EMPTY_MERKLE_ROOT: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+fn leaf_hash(tx_id: &str) -> String {
+    sha256::digest([&[LEAF_PREFIX], tx_id.as_bytes()].concat())
+}
+
+fn node_hash(left: &str, right: &str) -> String {
+    sha256::digest([&[NODE_PREFIX], left.as_bytes(), right.as_bytes()].concat())
+}
+
+fn next_level(level: &[String]) -> Vec<String> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(left, right),
+            [single] => single.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn merkle_root<S: AsRef<str>>(tx_ids: &[S]) -> String {
+    if tx_ids.is_empty() {
+        return EMPTY_MERKLE_ROOT.to_string();
+    }
+
+    let mut level: Vec<String> = tx_ids.iter().map(|id| leaf_hash(id.as_ref())).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+pub fn transactions_merkle_root(transactions: &[Transaction]) -> String {
+    let tx_ids: Vec<&str> = transactions.iter().map(|tx| tx.trx_id.as_str()).collect();
+    merkle_root(&tx_ids)
+}
+
+// commits the unlocking scripts too, which the ids leave out
+pub fn transactions_witness_root(transactions: &[Transaction]) -> String {
+    let witness_ids: Vec<String> = transactions.iter().map(|tx| tx.witness_id()).collect();
+    merkle_root(&witness_ids)
+}
+
+// Inclusion proof of a single leaf, in a tree of `size` leaves.
+// `siblings` are ordered from the leaf level up to (excluding) the root, a node moved up
+// without a sibling has none. `index` bits tell whether the running hash is the left(0)
+// or right(1) node on each level.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub size: usize,
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    pub fn build<S: AsRef<str>>(tx_ids: &[S], index: usize) -> Option<Self> {
+        if index >= tx_ids.len() {
+            return None;
+        }
+
+        let mut siblings = vec![];
+        let mut level: Vec<String> = tx_ids.iter().map(|id| leaf_hash(id.as_ref())).collect();
+        let mut pos = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(pos ^ 1) {
+                siblings.push(sibling.clone());
+            }
+
+            level = next_level(&level);
+            pos /= 2;
+        }
+
+        Some(Self {
+            index,
+            size: tx_ids.len(),
+            siblings,
+        })
+    }
+
+    // None when the siblings don't fit a tree of `size` leaves
+    pub fn root_of(&self, tx_id: &str) -> Option<String> {
+        if self.index >= self.size {
+            return None;
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut pos = self.index;
+        let mut len = self.size;
+        let mut hash = leaf_hash(tx_id);
+        while len > 1 {
+            if pos ^ 1 < len {
+                let sibling = siblings.next()?;
+                hash = if pos & 1 == 0 {
+                    node_hash(&hash, sibling)
+                } else {
+                    node_hash(sibling, &hash)
+                };
+            }
+            pos /= 2;
+            len = len.div_ceil(2);
+        }
+        match siblings.next() {
+            Some(_) => None,
+            None => Some(hash),
+        }
+    }
+
+    pub fn verify(&self, tx: &Transaction, merkle_root: &str) -> bool {
+        self.root_of(&tx.trx_id).as_deref() == Some(merkle_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("tx_{}", i)).collect()
+    }
+
+    #[test]
+    fn root_of_empty_and_single() {
+        let empty: Vec<String> = vec![];
+        assert_eq!(merkle_root(&empty), EMPTY_MERKLE_ROOT);
+        assert_eq!(merkle_root(&tx_ids(1)), leaf_hash("tx_0"));
+    }
+
+    #[test]
+    fn proof_for_every_leaf() {
+        for n in 1..=9 {
+            let ids = tx_ids(n);
+            let root = merkle_root(&ids);
+            for (i, id) in ids.iter().enumerate() {
+                let proof = MerkleProof::build(&ids, i).unwrap();
+                assert_eq!(proof.root_of(id), Some(root.clone()), "n={} i={}", n, i);
+            }
+            assert!(MerkleProof::build(&ids, n).is_none());
+        }
+    }
+
+    #[test]
+    fn proof_rejects_other_tx() {
+        let ids = tx_ids(5);
+        let root = merkle_root(&ids);
+        let proof = MerkleProof::build(&ids, 2).unwrap();
+        assert_ne!(proof.root_of("tx_3"), Some(root.clone()));
+        assert_ne!(proof.root_of("not_in_block"), Some(root.clone()));
+
+        // a proof for another tree size
+        let mut resized = proof.clone();
+        resized.size = 4;
+        assert_eq!(resized.root_of("tx_2"), None);
+        resized.size = 2;
+        assert_eq!(resized.root_of("tx_2"), None);
+    }
+
+    #[test]
+    fn roots_are_unambiguous() {
+        // the last id repeated
+        let mut repeated = tx_ids(3);
+        repeated.push("tx_2".into());
+        assert_ne!(merkle_root(&repeated), merkle_root(&tx_ids(3)));
+
+        // two hashes as an id don't pass for their parent
+        let ids = tx_ids(4);
+        let root = merkle_root(&ids);
+        let left = node_hash(&leaf_hash("tx_0"), &leaf_hash("tx_1"));
+        let right = node_hash(&leaf_hash("tx_2"), &leaf_hash("tx_3"));
+        assert_eq!(node_hash(&left, &right), root);
+        let forged = MerkleProof {
+            index: 0,
+            size: 2,
+            siblings: vec![right],
+        };
+        let inner_id = format!("{}{}", leaf_hash("tx_0"), leaf_hash("tx_1"));
+        assert_ne!(forged.root_of(&inner_id), Some(root));
+    }
+}
@@ -0,0 +1,128 @@
+use super::hd::HdErr;
+use bip39::Language;
+use k256::elliptic_curve::rand_core::{OsRng, RngCore};
+use std::fmt;
+use zeroize::Zeroizing;
+
+// BIP39 seed phrase, the human-writable backup of an HD wallet (see Wallet::from_mnemonic)
+// - 12 to 24 english words encoding 128 to 256 bits of entropy plus a checksum
+// - seed = pbkdf2-hmac-sha512(phrase, "mnemonic" ++ passphrase, 2048 rounds): a different
+//   passphrase restores different keys, there's no wrong one
+
+#[derive(Debug, PartialEq)]
+pub enum MnemonicErr {
+    WordCount(usize), // 12, 15, 18, 21 or 24
+    UnknownWord { index: usize, word: String },
+    InvalidChecksum, // a word was mistyped or swapped for another valid one
+    Hd(HdErr),
+}
+
+impl From<HdErr> for MnemonicErr {
+    fn from(e: HdErr) -> Self {
+        MnemonicErr::Hd(e)
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Mnemonic(bip39::Mnemonic);
+
+// the words are the keys, they're never printed
+impl fmt::Debug for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mnemonic")
+            .field("word_count", &self.word_count())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mnemonic {
+    // fresh random phrase of `word_count` words
+    pub fn generate(word_count: usize) -> Result<Self, MnemonicErr> {
+        if ![12, 15, 18, 21, 24].contains(&word_count) {
+            return Err(MnemonicErr::WordCount(word_count));
+        }
+        let mut entropy = Zeroizing::new([0u8; 32]);
+        let entropy = &mut entropy[..word_count / 3 * 4];
+        OsRng.fill_bytes(entropy);
+        let mnemonic = bip39::Mnemonic::from_entropy_in(Language::English, entropy)
+            .map_err(|_| MnemonicErr::WordCount(word_count))?;
+        Ok(Mnemonic(mnemonic))
+    }
+
+    // the words, space separated. extra whitespace and case are ignored
+    pub fn parse(phrase: &str) -> Result<Self, MnemonicErr> {
+        let normalized = Zeroizing::new(phrase.to_lowercase());
+        bip39::Mnemonic::parse_in(Language::English, normalized.as_str())
+            .map(Mnemonic)
+            .map_err(|e| match e {
+                bip39::Error::UnknownWord(index) => MnemonicErr::UnknownWord {
+                    index,
+                    word: normalized
+                        .split_whitespace()
+                        .nth(index)
+                        .unwrap_or_default()
+                        .to_string(),
+                },
+                bip39::Error::InvalidChecksum => MnemonicErr::InvalidChecksum,
+                _ => MnemonicErr::WordCount(normalized.split_whitespace().count()),
+            })
+    }
+
+    pub fn phrase(&self) -> Zeroizing<String> {
+        Zeroizing::new(self.0.to_string())
+    }
+
+    pub fn word_count(&self) -> usize {
+        self.0.word_count()
+    }
+
+    pub fn to_seed(&self, passphrase: &str) -> Zeroizing<[u8; 64]> {
+        Zeroizing::new(self.0.to_seed(passphrase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon \
+                          abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn generate_and_parse() {
+        for word_count in [12, 24] {
+            let mnemonic = Mnemonic::generate(word_count).unwrap();
+            assert_eq!(mnemonic.word_count(), word_count);
+            assert_eq!(Mnemonic::parse(&mnemonic.phrase()), Ok(mnemonic));
+        }
+        assert_eq!(Mnemonic::generate(13), Err(MnemonicErr::WordCount(13)));
+
+        // BIP39 test vector: entropy 0x00 * 16, passphrase "TREZOR"
+        let mnemonic = Mnemonic::parse(&PHRASE.to_uppercase()).unwrap();
+        assert_eq!(
+            hex::encode(mnemonic.to_seed("TREZOR")),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e5349553\
+             1f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+        assert_ne!(mnemonic.to_seed(""), mnemonic.to_seed("TREZOR"));
+    }
+
+    #[test]
+    fn invalid_phrase() {
+        assert_eq!(
+            Mnemonic::parse(&PHRASE.replace("about", "abuot")),
+            Err(MnemonicErr::UnknownWord {
+                index: 11,
+                word: "abuot".into()
+            })
+        );
+        assert_eq!(
+            Mnemonic::parse(&PHRASE.replace("about", "abandon")),
+            Err(MnemonicErr::InvalidChecksum)
+        );
+        assert_eq!(
+            Mnemonic::parse("abandon abandon about"),
+            Err(MnemonicErr::WordCount(3))
+        );
+    }
+}
@@ -0,0 +1,421 @@
+use super::cyphers::{PublicKey, Signature};
+use super::script::Script;
+use super::transaction::{SigHash, Transaction, TransactionData, TxOutput};
+use serde::{Deserialize, Serialize};
+
+// Partially signed transaction, passed around until every input can be unlocked
+// - carries the unsigned trx and, per input, the output it spends: a signer needs no chain
+// - signatures are collected per input and public key, from any number of parties
+// - combine: merge copies signed separately
+// - finalize: turn the signatures into unlocking scripts, once there are enough of them
+// - extract: the signed Transaction, ready for BlockChain::add_transaction
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartialSig {
+    pub public_key: PublicKey,
+    pub signature: Signature,
+    pub sighash: SigHash,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PsbtInput {
+    pub spent_output: TxOutput,
+    pub redeem_script: Option<Script>, // P2SH only, the script the output hash commits to
+    pub signatures: Vec<PartialSig>,
+    pub final_script: Option<Script>, // unlocking script, set by `finalize`
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Psbt {
+    unsigned_tx: TransactionData,
+    pub inputs: Vec<PsbtInput>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PsbtErr {
+    InvalidData,
+    AlreadySigned,
+    InputCountMismatch,
+    DifferentTransaction, // combining psbts of different trxs
+    MissingInput(usize),
+    NotASigner(usize), // the key can't unlock the input
+    InvalidSignature(usize),
+    InvalidRedeemScript(usize),
+    UnsupportedScript(usize),
+    NotEnoughSignatures(usize),
+    NotFinalized(usize),
+}
+
+impl Psbt {
+    // `tx` has to be unsigned, `spent_outputs` are the outputs of its inputs, in order
+    pub fn new(tx: &Transaction, spent_outputs: Vec<TxOutput>) -> Result<Self, PsbtErr> {
+        let unsigned_tx = tx.tx_data().map_err(|_| PsbtErr::InvalidData)?;
+        if unsigned_tx
+            .inputs
+            .iter()
+            .any(|input| !input.unlock_script.0.is_empty())
+        {
+            return Err(PsbtErr::AlreadySigned);
+        }
+        if unsigned_tx.inputs.len() != spent_outputs.len() {
+            return Err(PsbtErr::InputCountMismatch);
+        }
+        let inputs = spent_outputs
+            .into_iter()
+            .map(|spent_output| PsbtInput {
+                spent_output,
+                redeem_script: None,
+                signatures: vec![],
+                final_script: None,
+            })
+            .collect();
+        Ok(Self {
+            unsigned_tx,
+            inputs,
+        })
+    }
+
+    pub fn unsigned_tx(&self) -> &TransactionData {
+        &self.unsigned_tx
+    }
+
+    pub fn tx_id(&self) -> String {
+        self.unsigned_tx.tx_id()
+    }
+
+    // what a `sighash` signature of input `index` covers
+    pub fn signature_hash(&self, index: usize, sighash: SigHash) -> Option<Vec<u8>> {
+        self.unsigned_tx.signature_hash(index, sighash)
+    }
+
+    fn input(&self, index: usize) -> Result<&PsbtInput, PsbtErr> {
+        self.inputs.get(index).ok_or(PsbtErr::MissingInput(index))
+    }
+
+    // the redeem script has to hash to the P2SH output it spends
+    pub fn set_redeem_script(
+        &mut self,
+        index: usize,
+        redeem_script: Script,
+    ) -> Result<(), PsbtErr> {
+        let input = self
+            .inputs
+            .get_mut(index)
+            .ok_or(PsbtErr::MissingInput(index))?;
+        if input.spent_output.recipient_addr() != Some(redeem_script.script_hash_address()) {
+            return Err(PsbtErr::InvalidRedeemScript(index));
+        }
+        input.redeem_script = Some(redeem_script);
+        Ok(())
+    }
+
+    // keys able to sign input `index`, in the order their signatures are expected
+    pub fn signers(&self, index: usize) -> Result<Vec<PublicKey>, PsbtErr> {
+        let input = self.input(index)?;
+        match &input.redeem_script {
+            Some(redeem_script) => {
+                let (_, keys) = redeem_script
+                    .multisig_keys()
+                    .ok_or(PsbtErr::UnsupportedScript(index))?;
+                keys.into_iter()
+                    .map(|key| {
+                        PublicKey::from_sec1(key).map_err(|_| PsbtErr::UnsupportedScript(index))
+                    })
+                    .collect()
+            }
+            None if !input.spent_output.lock_script.is_p2pkh() => {
+                Err(PsbtErr::UnsupportedScript(index))
+            }
+            // P2PKH: the key hashing to the output, once it signed
+            None => Ok(input
+                .signatures
+                .iter()
+                .map(|sig| sig.public_key.clone())
+                .filter(|key| input.spent_output.pays_to(&key.address()))
+                .collect()),
+        }
+    }
+
+    fn can_sign(&self, index: usize, public_key: &PublicKey) -> Result<bool, PsbtErr> {
+        let input = self.input(index)?;
+        Ok(match &input.redeem_script {
+            Some(_) => self.signers(index)?.contains(public_key),
+            None => input.spent_output.pays_to(&public_key.address()),
+        })
+    }
+
+    // `signature` by `public_key` for input `index`, replaces an earlier one of the same key
+    pub fn add_signature(
+        &mut self,
+        index: usize,
+        public_key: PublicKey,
+        signature: Signature,
+        sighash: SigHash,
+    ) -> Result<(), PsbtErr> {
+        if !self.can_sign(index, &public_key)? {
+            return Err(PsbtErr::NotASigner(index));
+        }
+        let signed_data = self
+            .signature_hash(index, sighash)
+            .ok_or(PsbtErr::InvalidSignature(index))?;
+        public_key
+            .verify(&signature, &signed_data)
+            .map_err(|_| PsbtErr::InvalidSignature(index))?;
+
+        let input = &mut self.inputs[index];
+        input.signatures.retain(|sig| sig.public_key != public_key);
+        input.signatures.push(PartialSig {
+            public_key,
+            signature,
+            sighash,
+        });
+        input.final_script = None;
+        Ok(())
+    }
+
+    // merge the signatures and redeem scripts of `other`, a copy of the same trx
+    pub fn combine(&mut self, other: Psbt) -> Result<(), PsbtErr> {
+        if self.tx_id() != other.tx_id() || self.inputs.len() != other.inputs.len() {
+            return Err(PsbtErr::DifferentTransaction);
+        }
+        for (index, input) in other.inputs.into_iter().enumerate() {
+            if input.spent_output != self.inputs[index].spent_output {
+                return Err(PsbtErr::DifferentTransaction);
+            }
+            if let Some(redeem_script) = input.redeem_script {
+                self.set_redeem_script(index, redeem_script)?;
+            }
+            for sig in input.signatures {
+                self.add_signature(index, sig.public_key, sig.signature, sig.sighash)?;
+            }
+        }
+        Ok(())
+    }
+
+    // build the unlocking script of every input, all or nothing:
+    // fails on the first input without enough signatures
+    pub fn finalize(&mut self) -> Result<(), PsbtErr> {
+        let mut final_scripts = vec![];
+        for index in 0..self.inputs.len() {
+            let signers = self.signers(index)?;
+            let input = &self.inputs[index];
+            let signed_by = |key: &PublicKey| {
+                input
+                    .signatures
+                    .iter()
+                    .find(|sig| sig.public_key == *key)
+                    .map(|sig| (sig.signature.clone(), sig.sighash))
+            };
+
+            let final_script = match &input.redeem_script {
+                Some(redeem_script) => {
+                    let (m, _) = redeem_script
+                        .multisig_keys()
+                        .ok_or(PsbtErr::UnsupportedScript(index))?;
+                    let signatures: Vec<(Signature, SigHash)> = signers
+                        .iter()
+                        .filter_map(signed_by)
+                        .take(m as usize)
+                        .collect();
+                    if (signatures.len() as u64) < m {
+                        return Err(PsbtErr::NotEnoughSignatures(index));
+                    }
+                    Script::multisig_unlock(&signatures, redeem_script)
+                }
+                None => {
+                    let key = signers.last().ok_or(PsbtErr::NotEnoughSignatures(index))?;
+                    let (signature, sighash) = signed_by(key).unwrap();
+                    Script::p2pkh_unlock(&signature, sighash, key)
+                }
+            };
+            final_scripts.push(final_script);
+        }
+        for (input, final_script) in self.inputs.iter_mut().zip(final_scripts) {
+            input.final_script = Some(final_script);
+        }
+        Ok(())
+    }
+
+    // the signed trx, every input has to be finalized
+    pub fn extract(&self) -> Result<Transaction, PsbtErr> {
+        let mut data = self.unsigned_tx.clone();
+        for (index, input) in data.inputs.iter_mut().enumerate() {
+            input.unlock_script = self.inputs[index]
+                .final_script
+                .clone()
+                .ok_or(PsbtErr::NotFinalized(index))?;
+        }
+        Ok(Transaction {
+            trx_id: data.tx_id(),
+            data: bincode::serialize(&data).map_err(|_| PsbtErr::InvalidData)?,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, PsbtErr> {
+        bincode::deserialize(bytes).map_err(|_| PsbtErr::InvalidData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::script::{verify_script, ScriptContext};
+    use crate::core::transaction::{OutPoint, TxBuilder};
+    use crate::core::wallet::Wallet;
+
+    fn out(tx_id: &str) -> OutPoint {
+        OutPoint {
+            tx_id: tx_id.into(),
+            index: 0,
+        }
+    }
+
+    fn output(addr: &str) -> TxOutput {
+        TxOutput {
+            value: Amount::from_coins(1).unwrap(),
+            lock_script: Script::from_address(addr).unwrap(),
+        }
+    }
+
+    #[test]
+    fn hd_wallet_signs_derived_inputs() {
+        let mut w = Wallet::from_seed(&[5u8; 32]).unwrap();
+        let receive = w.fresh_address(false).unwrap();
+        let change = w.fresh_address(true).unwrap();
+        let other = Wallet::new(vec![]).unwrap();
+        let tx = TxBuilder::new()
+            .inputs(vec![
+                out("first"),
+                out("receive"),
+                out("change"),
+                out("other"),
+            ])
+            .output(other.address.clone(), Amount::from_coins(3).unwrap())
+            .build()
+            .unwrap();
+        let spent_outputs = vec![
+            output(&w.address),
+            output(&receive),
+            output(&change),
+            output(&other.address),
+        ];
+        let mut psbt = Psbt::new(&tx, spent_outputs).unwrap();
+
+        assert_eq!(w.sign_psbt(&mut psbt).unwrap(), 3);
+        assert_eq!(other.sign_psbt(&mut psbt).unwrap(), 1);
+        psbt.finalize().unwrap();
+        assert!(psbt.extract().unwrap().verify().is_ok());
+    }
+
+    #[test]
+    fn offline_multi_party_signing() {
+        let alice = Wallet::new(vec![]).unwrap();
+        let owners: Vec<Wallet> = (0..3).map(|_| Wallet::new(vec![]).unwrap()).collect();
+        let keys: Vec<PublicKey> = owners.iter().map(|w| w.public_key().clone()).collect();
+        let redeem_script = Script::multisig(2, &keys).unwrap();
+        let treasury = redeem_script.script_hash_address();
+
+        let tx = TxBuilder::new()
+            .inputs(vec![out("alice"), out("treasury")])
+            .output(alice.address.clone(), Amount::from_coins(2).unwrap())
+            .build()
+            .unwrap();
+        let spent_outputs = vec![output(&alice.address), output(&treasury)];
+        let mut psbt = Psbt::new(&tx, spent_outputs.clone()).unwrap();
+        assert_eq!(psbt.tx_id(), tx.trx_id);
+        assert_eq!(
+            psbt.set_redeem_script(0, redeem_script.clone()),
+            Err(PsbtErr::InvalidRedeemScript(0))
+        );
+        psbt.set_redeem_script(1, redeem_script).unwrap();
+
+        // each party signs its own copy, offline
+        let copies: Vec<Vec<u8>> = [&alice, &owners[2], &owners[0]]
+            .iter()
+            .map(|w| {
+                let mut copy = Psbt::decode(&psbt.encode()).unwrap();
+                assert_eq!(w.sign_psbt(&mut copy).unwrap(), 1);
+                copy.encode()
+            })
+            .collect();
+        assert_eq!(alice.sign_psbt(&mut psbt.clone()).unwrap(), 1);
+
+        psbt.combine(Psbt::decode(&copies[0]).unwrap()).unwrap();
+        psbt.combine(Psbt::decode(&copies[1]).unwrap()).unwrap();
+        assert_eq!(psbt.finalize(), Err(PsbtErr::NotEnoughSignatures(1)));
+        assert_eq!(psbt.extract().unwrap_err(), PsbtErr::NotFinalized(0));
+
+        psbt.combine(Psbt::decode(&copies[2]).unwrap()).unwrap();
+        psbt.finalize().unwrap();
+        let signed = psbt.extract().unwrap();
+        assert_eq!(signed.trx_id, tx.trx_id);
+        assert!(signed.verify().is_ok());
+
+        let data = signed.tx_data().unwrap();
+        for (input_index, (input, spent)) in data.inputs.iter().zip(&spent_outputs).enumerate() {
+            let ctx = ScriptContext {
+                tx: &data,
+                input_index,
+            };
+            assert_eq!(
+                verify_script(&input.unlock_script, &spent.lock_script, &ctx),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_foreign_data() {
+        let w = Wallet::new(vec![]).unwrap();
+        let other = Wallet::new(vec![]).unwrap();
+        let tx = TxBuilder::new()
+            .input(out("w"))
+            .output(w.address.clone(), Amount::from_coins(1).unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(
+            Psbt::new(&tx, vec![]).unwrap_err(),
+            PsbtErr::InputCountMismatch
+        );
+        let mut psbt = Psbt::new(&tx, vec![output(&w.address)]).unwrap();
+
+        assert_eq!(other.sign_psbt(&mut psbt).unwrap(), 0);
+        let signature = w.sign_data(b"something else").unwrap();
+        assert_eq!(
+            psbt.add_signature(0, w.public_key().clone(), signature, SigHash::All),
+            Err(PsbtErr::InvalidSignature(0))
+        );
+        assert_eq!(
+            psbt.add_signature(
+                2,
+                w.public_key().clone(),
+                w.sign_data(b"").unwrap(),
+                SigHash::All
+            ),
+            Err(PsbtErr::MissingInput(2))
+        );
+
+        let other_tx = TxBuilder::new()
+            .input(out("other"))
+            .output(w.address.clone(), Amount::from_coins(1).unwrap())
+            .build()
+            .unwrap();
+        let other_psbt = Psbt::new(&other_tx, vec![output(&w.address)]).unwrap();
+        assert_eq!(
+            psbt.clone().combine(other_psbt).unwrap_err(),
+            PsbtErr::DifferentTransaction
+        );
+
+        let mut signed = tx.clone();
+        w.sign_transaction(&mut signed).unwrap();
+        assert_eq!(
+            Psbt::new(&signed, vec![output(&w.address)]).unwrap_err(),
+            PsbtErr::AlreadySigned
+        );
+    }
+}
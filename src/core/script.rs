@@ -0,0 +1,685 @@
+use super::address::Address;
+use super::cyphers::{hash160, PublicKey, RecoverableSignature, Signature};
+use super::transaction::{LockTime, SigHash, TransactionData};
+use serde::{Deserialize, Serialize};
+
+// Spending conditions, a small stack language
+// - an output holds a locking script, the input spending it an unlocking script
+// - the unlocking script only pushes data, then the locking script runs on the same stack
+// - the spend is valid when nothing fails and the top of the stack is true (non zero)
+// - numbers are little endian u64, at most 8 bytes
+// - signatures are DER encoded followed by their sighash byte,
+//   they cover `TransactionData::signature_hash` of the input being unlocked
+// - pay to script hash (P2SH): the output only commits to the hash of a redeem script,
+//   the unlocking script pushes the encoded redeem script last and it runs after the lock
+// - compact P2PKH unlock: a recoverable signature alone, the key is recovered from it and
+//   the spend runs as <sig> <key> would
+
+// lock times below are block heights, from there on unix timestamps
+pub const LOCK_TIME_THRESHOLD: u64 = 500_000_000;
+pub const MAX_MULTISIG_KEYS: u64 = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OpCode {
+    Push(#[serde(with = "serde_bytes")] Vec<u8>),
+    Dup,
+    Hash160,     // replace the top with its ripemd160(sha256())
+    EqualVerify, // the two on top have to be equal, both are dropped
+    CheckSig,    // <sig> <pubkey>: the signature has to be valid, pushes true
+    // <sig_1>..<sig_m> <m> <key_1>..<key_n> <n>: every signature has to be valid,
+    // in the order of the keys, each key signs at most once. pushes true
+    CheckMultiSig,
+    CheckLockTimeVerify, // <lock>: the trx lock time has to be of the same kind and reach it
+    CheckSequenceVerify, // <blocks>: the input relative lock has to be at least that
+    Return,              // unspendable
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Script(pub Vec<OpCode>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptErr {
+    NotPushOnly, // unlocking script does more than pushing data
+    StackUnderflow,
+    InvalidNumber,
+    NotEqual,
+    InvalidRedeemScript,
+    InvalidPublicKey,
+    InvalidSignature,
+    InvalidMultiSig, // bad key or signature count
+    LockTimeNotReached,
+    SequenceNotReached,
+    Unspendable,
+    InvalidSigHash, // unknown sighash byte, or Single without a matching output
+    FalseResult,
+}
+
+// the spending trx and which of its inputs is being unlocked
+pub struct ScriptContext<'a> {
+    pub tx: &'a TransactionData,
+    pub input_index: usize,
+}
+
+// signature as scripts push it: DER encoded, then the sighash byte
+pub fn encode_sig(signature: &Signature, sighash: SigHash) -> Vec<u8> {
+    let mut bytes = signature.as_bytes();
+    bytes.push(sighash.to_byte());
+    bytes
+}
+
+pub fn decode_sig(bytes: &[u8]) -> Option<(Signature, SigHash)> {
+    let (sighash, der) = bytes.split_last()?;
+    Some((
+        Signature::from_der(der).ok()?,
+        SigHash::from_byte(*sighash)?,
+    ))
+}
+
+// recoverable signature as a compact P2PKH unlock pushes it, then the sighash byte
+pub fn encode_recoverable_sig(signature: &RecoverableSignature, sighash: SigHash) -> Vec<u8> {
+    let mut bytes = signature.as_bytes();
+    bytes.push(sighash.to_byte());
+    bytes
+}
+
+pub fn decode_recoverable_sig(bytes: &[u8]) -> Option<(RecoverableSignature, SigHash)> {
+    let (sighash, sig) = bytes.split_last()?;
+    Some((
+        RecoverableSignature::from_bytes(sig).ok()?,
+        SigHash::from_byte(*sighash)?,
+    ))
+}
+
+impl Script {
+    pub fn num(n: u64) -> OpCode {
+        OpCode::Push(n.to_le_bytes().to_vec())
+    }
+
+    // pay to public key hash: spent by a signature of the key hashing to `pubkey_hash`
+    pub fn p2pkh(pubkey_hash: &[u8; 20]) -> Self {
+        Self(vec![
+            OpCode::Dup,
+            OpCode::Hash160,
+            OpCode::Push(pubkey_hash.to_vec()),
+            OpCode::EqualVerify,
+            OpCode::CheckSig,
+        ])
+    }
+
+    // P2PKH to a key hash address, None for a malformed or P2SH one
+    pub fn p2pkh_addr(addr: &str) -> Option<Self> {
+        match addr.parse() {
+            Ok(Address::PubKeyHash(hash)) => Some(Self::p2pkh(&hash)),
+            _ => None,
+        }
+    }
+
+    // locking script paying to `addr`, P2PKH or P2SH. None for a malformed one
+    pub fn from_address(addr: &str) -> Option<Self> {
+        addr.parse().ok().map(|addr| Self::paying(&addr))
+    }
+
+    pub fn paying(addr: &Address) -> Self {
+        match addr {
+            Address::PubKeyHash(hash) => Self::p2pkh(hash),
+            Address::ScriptHash(hash) => Self::p2sh(hash),
+        }
+    }
+
+    // `m` of the `keys` have to sign, None when m isn't in 1..=keys.len()
+    // or there are more than MAX_MULTISIG_KEYS keys
+    pub fn multisig(m: u64, keys: &[PublicKey]) -> Option<Self> {
+        let n = keys.len() as u64;
+        if m == 0 || m > n || n > MAX_MULTISIG_KEYS {
+            return None;
+        }
+        let mut ops = vec![Self::num(m)];
+        ops.extend(keys.iter().map(|key| OpCode::Push(key.as_bytes())));
+        ops.extend([Self::num(n), OpCode::CheckMultiSig]);
+        Some(Self(ops))
+    }
+
+    // threshold and keys of a multisig redeem script
+    pub fn multisig_keys(&self) -> Option<(u64, Vec<&[u8]>)> {
+        match self.0.as_slice() {
+            [OpCode::Push(m), keys @ .., OpCode::Push(n), OpCode::CheckMultiSig] => {
+                let keys: Vec<&[u8]> = keys
+                    .iter()
+                    .map(|op| match op {
+                        OpCode::Push(key) => Some(key.as_slice()),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+                let (m, n) = (as_num(m)?, as_num(n)?);
+                (n == keys.len() as u64 && m <= n).then_some((m, keys))
+            }
+            _ => None,
+        }
+    }
+
+    // pay to the hash of `redeem_script`
+    pub fn p2sh(script_hash: &[u8; 20]) -> Self {
+        Self(vec![
+            OpCode::Hash160,
+            OpCode::Push(script_hash.to_vec()),
+            OpCode::EqualVerify,
+        ])
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    // P2SH address of this redeem script
+    pub fn script_hash_address(&self) -> String {
+        Address::ScriptHash(hash160(&self.encode())).to_string()
+    }
+
+    // signatures in the order of the keys, then the redeem script
+    pub fn multisig_unlock(signatures: &[(Signature, SigHash)], redeem_script: &Script) -> Self {
+        let mut ops: Vec<OpCode> = signatures
+            .iter()
+            .map(|(sig, sighash)| OpCode::Push(encode_sig(sig, *sighash)))
+            .collect();
+        ops.push(OpCode::Push(redeem_script.encode()));
+        Self(ops)
+    }
+
+    pub fn p2pkh_unlock(signature: &Signature, sighash: SigHash, public_key: &PublicKey) -> Self {
+        Self(vec![
+            OpCode::Push(encode_sig(signature, sighash)),
+            OpCode::Push(public_key.as_bytes()),
+        ])
+    }
+
+    // P2PKH unlock without the public key
+    pub fn p2pkh_compact_unlock(signature: &RecoverableSignature, sighash: SigHash) -> Self {
+        Self(vec![OpCode::Push(encode_recoverable_sig(
+            signature, sighash,
+        ))])
+    }
+
+    // <sig> <key> of a compact P2PKH unlock, the key recovered from what `sig` signed.
+    // None when it's not one.
+    pub fn expand_compact(&self, ctx: &ScriptContext) -> Option<Self> {
+        let [OpCode::Push(bytes)] = self.0.as_slice() else {
+            return None;
+        };
+        let (signature, sighash) = decode_recoverable_sig(bytes)?;
+        let data = ctx.tx.signature_hash(ctx.input_index, sighash)?;
+        let key = PublicKey::recover(&data, &signature).ok()?;
+        Some(Self::p2pkh_unlock(&signature.signature(), sighash, &key))
+    }
+
+    pub fn unspendable() -> Self {
+        Self(vec![OpCode::Return])
+    }
+
+    pub fn is_p2pkh(&self) -> bool {
+        matches!(
+            self.0.as_slice(),
+            [OpCode::Dup, OpCode::Hash160, OpCode::Push(hash), OpCode::EqualVerify, OpCode::CheckSig]
+                if hash.len() == 20
+        )
+    }
+
+    fn is_p2sh(&self) -> bool {
+        matches!(
+            self.0.as_slice(),
+            [OpCode::Hash160, OpCode::Push(hash), OpCode::EqualVerify] if hash.len() == 20
+        )
+    }
+
+    // address a P2PKH or P2SH locking script pays to
+    pub fn address(&self) -> Option<String> {
+        match self.0.as_slice() {
+            [OpCode::Dup, OpCode::Hash160, OpCode::Push(hash), OpCode::EqualVerify, OpCode::CheckSig]
+                if hash.len() == 20 =>
+            {
+                Some(Address::PubKeyHash(hash.as_slice().try_into().unwrap()).to_string())
+            }
+            [OpCode::Hash160, OpCode::Push(hash), OpCode::EqualVerify] if hash.len() == 20 => {
+                Some(Address::ScriptHash(hash.as_slice().try_into().unwrap()).to_string())
+            }
+            _ => None,
+        }
+    }
+
+    // address an unlocking script spends from:
+    // the key of a P2PKH one, the redeem script of a P2SH one
+    pub fn signer_address(&self) -> Option<String> {
+        if let [OpCode::Push(_), OpCode::Push(key)] = self.0.as_slice() {
+            if let Ok(key) = PublicKey::from_sec1(key) {
+                return Some(key.address());
+            }
+        }
+        match self.0.last() {
+            Some(OpCode::Push(redeem)) => bincode::deserialize::<Script>(redeem)
+                .ok()
+                .map(|redeem| redeem.script_hash_address()),
+            _ => None,
+        }
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.0.iter().all(|op| matches!(op, OpCode::Push(_)))
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptErr> {
+    stack.pop().ok_or(ScriptErr::StackUnderflow)
+}
+
+fn as_num(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() > 8 {
+        return None;
+    }
+    let mut le = [0u8; 8];
+    le[..bytes.len()].copy_from_slice(bytes);
+    Some(u64::from_le_bytes(le))
+}
+
+fn pop_num(stack: &mut Vec<Vec<u8>>) -> Result<u64, ScriptErr> {
+    as_num(&pop(stack)?).ok_or(ScriptErr::InvalidNumber)
+}
+
+fn is_true(bytes: &[u8]) -> bool {
+    bytes.iter().any(|b| *b != 0)
+}
+
+fn check_sig(sig: &[u8], key: &[u8], ctx: &ScriptContext) -> Result<bool, ScriptErr> {
+    let key = PublicKey::from_sec1(key).map_err(|_| ScriptErr::InvalidPublicKey)?;
+    let Some((sighash, der)) = sig.split_last() else {
+        return Ok(false);
+    };
+    let data = SigHash::from_byte(*sighash)
+        .and_then(|sighash| ctx.tx.signature_hash(ctx.input_index, sighash))
+        .ok_or(ScriptErr::InvalidSigHash)?;
+    Ok(Signature::from_der(der)
+        .and_then(|sig| key.verify(&sig, &data))
+        .is_ok())
+}
+
+fn check_multi_sig(stack: &mut Vec<Vec<u8>>, ctx: &ScriptContext) -> Result<(), ScriptErr> {
+    let n = pop_num(stack)?;
+    if n == 0 || n > MAX_MULTISIG_KEYS {
+        return Err(ScriptErr::InvalidMultiSig);
+    }
+    let mut keys = (0..n).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+    keys.reverse();
+    let m = pop_num(stack)?;
+    if m == 0 || m > n {
+        return Err(ScriptErr::InvalidMultiSig);
+    }
+    let mut sigs = (0..m).map(|_| pop(stack)).collect::<Result<Vec<_>, _>>()?;
+    sigs.reverse();
+
+    // keys only move forward: a key can't sign twice, signatures follow the key order
+    let mut keys = keys.iter();
+    for sig in &sigs {
+        loop {
+            let key = keys.next().ok_or(ScriptErr::InvalidSignature)?;
+            if check_sig(sig, key, ctx)? {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_lock_time(lock: u64, ctx: &ScriptContext) -> Result<(), ScriptErr> {
+    let reached = match ctx.tx.lock_time {
+        Some(LockTime::Height(height)) => lock < LOCK_TIME_THRESHOLD && height >= lock,
+        Some(LockTime::Time(time)) => lock >= LOCK_TIME_THRESHOLD && time >= lock as i64,
+        None => false,
+    };
+    match reached {
+        true => Ok(()),
+        false => Err(ScriptErr::LockTimeNotReached),
+    }
+}
+
+fn execute(
+    script: &Script,
+    stack: &mut Vec<Vec<u8>>,
+    ctx: &ScriptContext,
+) -> Result<(), ScriptErr> {
+    for op in &script.0 {
+        match op {
+            OpCode::Push(bytes) => stack.push(bytes.clone()),
+            OpCode::Dup => {
+                let top = stack.last().ok_or(ScriptErr::StackUnderflow)?.clone();
+                stack.push(top);
+            }
+            OpCode::Hash160 => {
+                let top = pop(stack)?;
+                stack.push(hash160(&top).to_vec());
+            }
+            OpCode::EqualVerify => {
+                if pop(stack)? != pop(stack)? {
+                    return Err(ScriptErr::NotEqual);
+                }
+            }
+            OpCode::CheckSig => {
+                let key = pop(stack)?;
+                let sig = pop(stack)?;
+                if !check_sig(&sig, &key, ctx)? {
+                    return Err(ScriptErr::InvalidSignature);
+                }
+                stack.push(vec![1]);
+            }
+            OpCode::CheckMultiSig => {
+                check_multi_sig(stack, ctx)?;
+                stack.push(vec![1]);
+            }
+            OpCode::CheckLockTimeVerify => check_lock_time(pop_num(stack)?, ctx)?,
+            OpCode::CheckSequenceVerify => {
+                let blocks = pop_num(stack)?;
+                let input = ctx.tx.inputs.get(ctx.input_index);
+                if input.and_then(|input| input.relative_lock).unwrap_or(0) < blocks {
+                    return Err(ScriptErr::SequenceNotReached);
+                }
+            }
+            OpCode::Return => return Err(ScriptErr::Unspendable),
+        }
+    }
+    Ok(())
+}
+
+// run `unlock` then `lock` on a shared stack
+pub fn verify_script(unlock: &Script, lock: &Script, ctx: &ScriptContext) -> Result<(), ScriptErr> {
+    if !unlock.is_push_only() {
+        return Err(ScriptErr::NotPushOnly);
+    }
+    let expanded = match lock.is_p2pkh() {
+        true => unlock.expand_compact(ctx),
+        false => None,
+    };
+    let unlock = expanded.as_ref().unwrap_or(unlock);
+    let mut stack = vec![];
+    execute(unlock, &mut stack, ctx)?;
+    let redeem = match lock.is_p2sh() {
+        true => stack.last().cloned(),
+        false => None,
+    };
+    execute(lock, &mut stack, ctx)?;
+    if let Some(redeem) = redeem {
+        let redeem: Script =
+            bincode::deserialize(&redeem).map_err(|_| ScriptErr::InvalidRedeemScript)?;
+        execute(&redeem, &mut stack, ctx)?;
+    }
+    match stack.last() {
+        Some(top) if is_true(top) => Ok(()),
+        _ => Err(ScriptErr::FalseResult),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::amount::Amount;
+    use crate::core::cyphers::PrivateKey;
+    use crate::core::transaction::{OutPoint, TxInput, TxOutput};
+
+    fn tx_data() -> TransactionData {
+        TransactionData {
+            inputs: vec![TxInput::new(OutPoint {
+                tx_id: "coins".into(),
+                index: 0,
+            })],
+            outputs: vec![],
+            lock_time: None,
+            replaceable: false,
+            coinbase_height: None,
+        }
+    }
+
+    fn ctx(tx: &TransactionData) -> ScriptContext<'_> {
+        ScriptContext { tx, input_index: 0 }
+    }
+
+    // signature of the first input of `tx`, as pushed by an unlocking script
+    fn sign(key: &PrivateKey, tx: &TransactionData) -> Signature {
+        key.sign(&tx.signature_hash(0, SigHash::All).unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn p2pkh() {
+        let key = PrivateKey::generate().unwrap();
+        let other = PrivateKey::generate().unwrap();
+        let lock = Script::p2pkh(&key.public_key().hash());
+        assert_eq!(lock.address(), Some(key.public_key().address()));
+        assert_eq!(
+            Script::p2pkh_addr(&key.public_key().address()),
+            Some(lock.clone())
+        );
+        assert_eq!(Script::p2pkh_addr("not an address"), None);
+        let mut typo = key.public_key().address();
+        typo.replace_range(5..6, if &typo[5..6] == "x" { "y" } else { "x" });
+        assert_eq!(Script::from_address(&typo), None);
+
+        let data = &tx_data();
+        let unlock = Script::p2pkh_unlock(&sign(&key, data), SigHash::All, &key.public_key());
+        assert_eq!(verify_script(&unlock, &lock, &ctx(data)), Ok(()));
+        assert_eq!(unlock.signer_address(), Some(key.public_key().address()));
+
+        // compact: the key is recovered from the signature
+        let signed_data = data.signature_hash(0, SigHash::All).unwrap();
+        let recoverable = key.sign_recoverable(&signed_data).unwrap();
+        let compact = Script::p2pkh_compact_unlock(&recoverable, SigHash::All);
+        assert_eq!(verify_script(&compact, &lock, &ctx(data)), Ok(()));
+        assert_eq!(
+            compact.expand_compact(&ctx(data)).unwrap().signer_address(),
+            Some(key.public_key().address())
+        );
+        let other_lock = Script::p2pkh(&other.public_key().hash());
+        assert_eq!(
+            verify_script(&compact, &other_lock, &ctx(data)),
+            Err(ScriptErr::NotEqual)
+        );
+        assert_eq!(unlock.expand_compact(&ctx(data)), None);
+
+        let mut other_data = tx_data();
+        other_data.replaceable = true;
+        assert_eq!(
+            verify_script(&unlock, &lock, &ctx(&other_data)),
+            Err(ScriptErr::InvalidSignature)
+        );
+        let not_owner =
+            Script::p2pkh_unlock(&sign(&other, data), SigHash::All, &other.public_key());
+        assert_eq!(
+            verify_script(&not_owner, &lock, &ctx(data)),
+            Err(ScriptErr::NotEqual)
+        );
+        assert_eq!(
+            verify_script(&Script::default(), &lock, &ctx(data)),
+            Err(ScriptErr::StackUnderflow)
+        );
+        let mut not_push_only = unlock.clone();
+        not_push_only.0.push(OpCode::Dup);
+        assert_eq!(
+            verify_script(&not_push_only, &lock, &ctx(data)),
+            Err(ScriptErr::NotPushOnly)
+        );
+        assert_eq!(
+            verify_script(&unlock, &Script::unspendable(), &ctx(data)),
+            Err(ScriptErr::Unspendable)
+        );
+    }
+
+    #[test]
+    fn multi_sig() {
+        let keys: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::generate().unwrap()).collect();
+        let data = &tx_data();
+        let mut lock = vec![Script::num(2)];
+        lock.extend(keys.iter().map(|k| OpCode::Push(k.public_key().as_bytes())));
+        lock.extend([Script::num(3), OpCode::CheckMultiSig]);
+        let lock = Script(lock);
+
+        let unlock = |signers: &[usize]| {
+            Script(
+                signers
+                    .iter()
+                    .map(|i| OpCode::Push(encode_sig(&sign(&keys[*i], data), SigHash::All)))
+                    .collect(),
+            )
+        };
+        assert_eq!(verify_script(&unlock(&[0, 2]), &lock, &ctx(data)), Ok(()));
+        assert_eq!(verify_script(&unlock(&[1, 2]), &lock, &ctx(data)), Ok(()));
+        // out of key order, or the same key twice
+        assert_eq!(
+            verify_script(&unlock(&[2, 0]), &lock, &ctx(data)),
+            Err(ScriptErr::InvalidSignature)
+        );
+        assert_eq!(
+            verify_script(&unlock(&[1, 1]), &lock, &ctx(data)),
+            Err(ScriptErr::InvalidSignature)
+        );
+        assert_eq!(
+            verify_script(&unlock(&[0]), &lock, &ctx(data)),
+            Err(ScriptErr::StackUnderflow)
+        );
+        let mut more_sigs_than_keys = lock.clone();
+        more_sigs_than_keys.0[0] = Script::num(4);
+        assert_eq!(
+            verify_script(&unlock(&[0, 1, 2]), &more_sigs_than_keys, &ctx(data)),
+            Err(ScriptErr::InvalidMultiSig)
+        );
+    }
+
+    #[test]
+    fn p2sh() {
+        let keys: Vec<PrivateKey> = (0..2).map(|_| PrivateKey::generate().unwrap()).collect();
+        let public_keys: Vec<PublicKey> = keys.iter().map(|k| k.public_key()).collect();
+        let redeem = Script::multisig(1, &public_keys).unwrap();
+        assert_eq!(
+            redeem.multisig_keys().map(|(m, keys)| (m, keys.len())),
+            Some((1, 2))
+        );
+
+        let addr = redeem.script_hash_address();
+        let lock = Script::from_address(&addr).unwrap();
+        assert_eq!(lock.address(), Some(addr.clone()));
+        assert!(addr.starts_with('3'));
+        assert_eq!(Script::p2pkh_addr(&addr), None);
+        assert_eq!(
+            Script::from_address(&public_keys[0].address()),
+            Script::p2pkh_addr(&public_keys[0].address())
+        );
+
+        let data = &tx_data();
+        let signatures = [(sign(&keys[1], data), SigHash::All)];
+        let unlock = Script::multisig_unlock(&signatures, &redeem);
+        assert_eq!(unlock.signer_address(), Some(addr));
+        assert_eq!(verify_script(&unlock, &lock, &ctx(data)), Ok(()));
+
+        // another redeem script than the one committed to
+        let other = Script::multisig(2, &public_keys).unwrap();
+        let unlock = Script::multisig_unlock(&signatures, &other);
+        assert_eq!(
+            verify_script(&unlock, &lock, &ctx(data)),
+            Err(ScriptErr::NotEqual)
+        );
+    }
+
+    #[test]
+    fn sighash_modes() {
+        let key = PrivateKey::generate().unwrap();
+        let lock = Script::p2pkh(&key.public_key().hash());
+        let mut tx = tx_data();
+        tx.outputs = vec![TxOutput {
+            value: Amount::from_coins(1).unwrap(),
+            lock_script: lock.clone(),
+        }];
+        let unlock = |tx: &TransactionData, sighash: SigHash| {
+            let signed_data = tx.signature_hash(0, sighash).unwrap();
+            Script::p2pkh_unlock(&key.sign(&signed_data).unwrap(), sighash, &key.public_key())
+        };
+        let all = unlock(&tx, SigHash::All);
+        let anyone_can_pay = unlock(&tx, SigHash::AllAnyoneCanPay);
+        let single = unlock(&tx, SigHash::Single);
+        assert_eq!(
+            decode_sig(&encode_sig(&sign(&key, &tx), SigHash::Single)).map(|(_, h)| h),
+            Some(SigHash::Single)
+        );
+
+        // another input: only the AnyoneCanPay signature still holds
+        let mut more_inputs = tx.clone();
+        more_inputs.inputs.push(TxInput::new(OutPoint {
+            tx_id: "other coins".into(),
+            index: 0,
+        }));
+        assert_eq!(
+            verify_script(&anyone_can_pay, &lock, &ctx(&more_inputs)),
+            Ok(())
+        );
+        assert_eq!(
+            verify_script(&all, &lock, &ctx(&more_inputs)),
+            Err(ScriptErr::InvalidSignature)
+        );
+
+        // another output: only the Single signature still holds
+        let mut more_outputs = tx.clone();
+        more_outputs.outputs.push(more_outputs.outputs[0].clone());
+        assert_eq!(verify_script(&single, &lock, &ctx(&more_outputs)), Ok(()));
+        assert_eq!(
+            verify_script(&anyone_can_pay, &lock, &ctx(&more_outputs)),
+            Err(ScriptErr::InvalidSignature)
+        );
+
+        // no output to sign with Single, or an unknown sighash byte
+        assert_eq!(tx_data().signature_hash(0, SigHash::Single), None);
+        let mut unknown = single.clone();
+        if let OpCode::Push(sig) = &mut unknown.0[0] {
+            *sig.last_mut().unwrap() = 0x02;
+        }
+        assert_eq!(
+            verify_script(&unknown, &lock, &ctx(&tx)),
+            Err(ScriptErr::InvalidSigHash)
+        );
+    }
+
+    #[test]
+    fn timelocks() {
+        let key = PrivateKey::generate().unwrap();
+        let unlock = |tx: &TransactionData| {
+            Script(vec![OpCode::Push(encode_sig(
+                &sign(&key, tx),
+                SigHash::All,
+            ))])
+        };
+        let lock = |ops: Vec<OpCode>| {
+            let mut script = ops;
+            script.extend([OpCode::Push(key.public_key().as_bytes()), OpCode::CheckSig]);
+            Script(script)
+        };
+        let after_height = lock(vec![Script::num(10), OpCode::CheckLockTimeVerify]);
+        let after_blocks = lock(vec![Script::num(3), OpCode::CheckSequenceVerify]);
+
+        let mut tx = tx_data();
+        for lock_time in [None, Some(LockTime::Height(9)), Some(LockTime::Time(10))] {
+            tx.lock_time = lock_time;
+            assert_eq!(
+                verify_script(&unlock(&tx), &after_height, &ctx(&tx)),
+                Err(ScriptErr::LockTimeNotReached)
+            );
+        }
+        tx.lock_time = Some(LockTime::Height(10));
+        assert_eq!(
+            verify_script(&unlock(&tx), &after_height, &ctx(&tx)),
+            Ok(())
+        );
+
+        tx.inputs[0].relative_lock = Some(2);
+        assert_eq!(
+            verify_script(&unlock(&tx), &after_blocks, &ctx(&tx)),
+            Err(ScriptErr::SequenceNotReached)
+        );
+        tx.inputs[0].relative_lock = Some(3);
+        assert_eq!(
+            verify_script(&unlock(&tx), &after_blocks, &ctx(&tx)),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use super::block::Block;
+use super::cyphers::Encoder;
+use super::transaction::Transaction;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+// On-disk layout of a node's data dir
+// - blocks.dat: append-only log of every accepted block (active and side branches),
+//   parents are always written before children so the log can be replayed in order.
+//   record = payload_len(4, LE) ++ sha256(payload)(32) ++ payload(Block::encode)
+// - mem_pool.dat: snapshot of pending trxs, replaced atomically (write tmp -> rename)
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const MEM_POOL_FILE: &str = "mem_pool.dat";
+const RECORD_HEADER_SIZE: usize = 36;
+
+#[derive(Debug)]
+pub enum StorageErr {
+    Io(std::io::Error),
+    Encode(String),
+    Corrupted { offset: usize }, // a bad record followed by others
+    GenesisMismatch { stored: String, expected: String }, // a dir of another chain
+}
+
+impl From<std::io::Error> for StorageErr {
+    fn from(e: std::io::Error) -> Self {
+        StorageErr::Io(e)
+    }
+}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    hex::decode(sha256::digest(payload)).unwrap()
+}
+
+// decode every record, returns the blocks and the length of the valid prefix.
+// only the last record may be bad, torn by a crash while it was appended: a bad one
+// followed by others is corruption, the blocks after it aren't dropped silently.
+fn read_records(bytes: &[u8]) -> Result<(Vec<Block>, usize), StorageErr> {
+    let mut blocks = vec![];
+    let mut offset = 0;
+    while bytes.len() - offset >= RECORD_HEADER_SIZE {
+        let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let start = offset + RECORD_HEADER_SIZE;
+        if bytes.len() - start < len {
+            break;
+        }
+
+        let payload = &bytes[start..start + len];
+        let decoded = if checksum(payload) == bytes[offset + 4..start] {
+            bincode::deserialize::<Block>(payload).ok()
+        } else {
+            None
+        };
+        match decoded {
+            Some(b) => blocks.push(b),
+            None if start + len == bytes.len() => break,
+            None => return Err(StorageErr::Corrupted { offset }),
+        }
+        offset = start + len;
+    }
+    Ok((blocks, offset))
+}
+
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    blocks: File,
+}
+
+impl BlockStore {
+    // open (or create) the store in `dir`, returns the stored blocks in append order.
+    // a record left half written by a crash is cut off the end of the log, a corrupted
+    // one anywhere else fails the open and leaves the log as is.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<(Self, Vec<Block>), StorageErr> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut blocks = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCKS_FILE))?;
+        let mut bytes = vec![];
+        blocks.read_to_end(&mut bytes)?;
+
+        let (stored, valid_len) = read_records(&bytes)?;
+        if valid_len < bytes.len() {
+            blocks.set_len(valid_len as u64)?;
+            blocks.sync_all()?;
+        }
+
+        Ok((Self { dir, blocks }, stored))
+    }
+
+    // the block is durable once this returns
+    pub fn append_block(&mut self, b: &Block) -> Result<(), StorageErr> {
+        let payload = b.encode().map_err(StorageErr::Encode)?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+
+        self.blocks.write_all(&record)?;
+        self.blocks.sync_data()?;
+        Ok(())
+    }
+
+    pub fn save_mem_pool(&self, mem_pool: &[Transaction]) -> Result<(), StorageErr> {
+        let encoded =
+            bincode::serialize(mem_pool).map_err(|e| StorageErr::Encode(e.to_string()))?;
+
+        let tmp_path = self.dir.join(format!("{}.tmp", MEM_POOL_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&encoded)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(MEM_POOL_FILE))?;
+        Ok(())
+    }
+
+    // the mem_pool is only a cache of pending trxs, an unreadable snapshot is dropped
+    pub fn load_mem_pool(&self) -> Result<Vec<Transaction>, StorageErr> {
+        match fs::read(self.dir.join(MEM_POOL_FILE)) {
+            Ok(bytes) => Ok(bincode::deserialize(&bytes).unwrap_or_default()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(StorageErr::Io(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("bchain-{}", xid::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::block::ZERO_HASH;
+
+    #[test]
+    fn torn_record_is_truncated() {
+        let dir = temp_dir();
+        let (mut store, stored) = BlockStore::open(&dir).unwrap();
+        assert!(stored.is_empty());
+
+        let mut b = Block::new(ZERO_HASH.into(), 0, 0, vec![]);
+        b.gen_hash().unwrap();
+        store.append_block(&b).unwrap();
+        store.append_block(&b).unwrap();
+        drop(store);
+
+        // crash in the middle of the 2nd record
+        let path = dir.join(BLOCKS_FILE);
+        let full_len = fs::metadata(&path).unwrap().len();
+        let record_len = full_len / 2;
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 5).unwrap();
+        drop(file);
+
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].hash(), b.hash());
+        assert_eq!(fs::metadata(&path).unwrap().len(), record_len);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_last_record_is_dropped() {
+        let dir = temp_dir();
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        let mut b = Block::new(ZERO_HASH.into(), 0, 0, vec![]);
+        b.gen_hash().unwrap();
+        store.append_block(&b).unwrap();
+        drop(store);
+
+        let path = dir.join(BLOCKS_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let (_, stored) = BlockStore::open(&dir).unwrap();
+        assert!(stored.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_record_isnt_truncated() {
+        let dir = temp_dir();
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        let mut b = Block::new(ZERO_HASH.into(), 0, 0, vec![]);
+        b.gen_hash().unwrap();
+        store.append_block(&b).unwrap();
+        store.append_block(&b).unwrap();
+        drop(store);
+
+        // the 1st record's payload, the 2nd is intact
+        let path = dir.join(BLOCKS_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[RECORD_HEADER_SIZE] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(matches!(
+            BlockStore::open(&dir),
+            Err(StorageErr::Corrupted { offset: 0 })
+        ));
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
-use super::cyphers::{Decoder, Encoder, Signature};
+use super::amount::Amount;
+use super::cyphers::{Decoder, Encoder};
+use super::script::{Script, ScriptContext, ScriptErr};
 use serde::{Deserialize, Serialize};
 
 // Transaction struct
@@ -12,21 +14,262 @@ use serde::{Deserialize, Serialize};
 //         It's typically the cryptographic hash of the entire transaction's data structure (excluding the TxId itself).
 //         This is calculated *after* the transaction is formed.
 
+// output `index` of the trx `tx_id`
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub tx_id: String,
+    pub index: u32,
+}
+
+// earliest block a trx can be included in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LockTime {
+    Height(u64), // block height
+    Time(i64),   // unix timestamp, compared to the median time past of the block
+}
+
+// spends the output `prev_out`, `unlock_script` has to satisfy its locking script
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxInput {
+    pub prev_out: OutPoint,
+    pub relative_lock: Option<u64>, // blocks `prev_out` has to be confirmed for before it's spent
+    pub unlock_script: Script,      // not covered by the id nor the signatures
+}
+
+impl TxInput {
+    pub fn new(prev_out: OutPoint) -> Self {
+        Self {
+            prev_out,
+            relative_lock: None,
+            unlock_script: Script::default(),
+        }
+    }
+
+    // spendable by a block at `height` when `prev_out` was confirmed at `prev_height`
+    pub fn is_unlocked(&self, prev_height: u64, height: u64) -> bool {
+        match self.relative_lock {
+            Some(blocks) => height >= prev_height.saturating_add(blocks),
+            None => true,
+        }
+    }
+}
+
+// `value` spendable by whoever satisfies `lock_script`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxOutput {
+    pub value: Amount,
+    pub lock_script: Script,
+}
+
+impl TxOutput {
+    // address of a P2PKH or P2SH output
+    pub fn recipient_addr(&self) -> Option<String> {
+        self.lock_script.address()
+    }
+
+    pub fn pays_to(&self, addr: &str) -> bool {
+        self.recipient_addr().as_deref() == Some(addr)
+    }
+}
+
+// what of the trx a signature commits to, pushed as the last byte of the signature
+// - All: every input and output
+// - Single: every input, and only the output at the index of the signed input
+// - AnyoneCanPay: only the signed input, others can be added freely (crowdfunding)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigHash {
+    All,
+    Single,
+    AllAnyoneCanPay,
+    SingleAnyoneCanPay,
+}
+
+impl SigHash {
+    const ANYONE_CAN_PAY: u8 = 0x80;
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            SigHash::All => 0x01,
+            SigHash::Single => 0x03,
+            SigHash::AllAnyoneCanPay => 0x01 | Self::ANYONE_CAN_PAY,
+            SigHash::SingleAnyoneCanPay => 0x03 | Self::ANYONE_CAN_PAY,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        [
+            SigHash::All,
+            SigHash::Single,
+            SigHash::AllAnyoneCanPay,
+            SigHash::SingleAnyoneCanPay,
+        ]
+        .into_iter()
+        .find(|sighash| sighash.to_byte() == byte)
+    }
+
+    pub fn anyone_can_pay(self) -> bool {
+        self.to_byte() & Self::ANYONE_CAN_PAY != 0
+    }
+
+    pub fn single(self) -> bool {
+        matches!(self, SigHash::Single | SigHash::SingleAnyoneCanPay)
+    }
+}
+
+// fee paid to the miner = value of the inputs - value of the outputs
 #[derive(Debug, Serialize, Deserialize, Clone)]
-struct TransactionData {
-    sender_addr: String,
-    receiver_addr: String,
-    value: f64,
+pub struct TransactionData {
+    pub inputs: Vec<TxInput>,
+    pub outputs: Vec<TxOutput>,
 
-    inputs: Vec<Transaction>,
-    outputs: Vec<Transaction>,
+    pub lock_time: Option<LockTime>,
+    pub replaceable: bool, // opt in to be replaced by a higher fee trx while pending
+    pub coinbase_height: Option<u64>, // coinbase only: height of the rewarded block
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl TransactionData {
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.is_empty() && self.coinbase_height.is_some()
+    }
+
+    // can be included in the block at `height`, `median_time_past` being the one of its parent
+    pub fn is_final(&self, height: u64, median_time_past: i64) -> bool {
+        match self.lock_time {
+            Some(LockTime::Height(lock_height)) => height >= lock_height,
+            Some(LockTime::Time(lock_time)) => median_time_past >= lock_time,
+            None => true,
+        }
+    }
+
+    pub fn output_value(&self) -> Option<Amount> {
+        Amount::checked_sum(self.outputs.iter().map(|output| output.value))
+    }
+
+    // the whole trx without its unlocking data, what the id and the All signatures cover
+    pub fn signing_data(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.unlock_script = Script::default();
+        }
+        bincode::serialize(&unsigned).unwrap()
+    }
+
+    // what a `sighash` signature of input `index` covers: the signing data, without what
+    // `sighash` leaves out, then the sighash byte (and `index` when it's not All).
+    // None when there's no input `index`, or no output `index` to sign with Single.
+    pub fn signature_hash(&self, index: usize, sighash: SigHash) -> Option<Vec<u8>> {
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.unlock_script = Script::default();
+        }
+        let input = unsigned.inputs.get(index)?.clone();
+        if sighash.anyone_can_pay() {
+            unsigned.inputs = vec![input];
+        }
+        if sighash.single() {
+            unsigned.outputs = vec![unsigned.outputs.get(index)?.clone()];
+        }
+
+        let mut data = bincode::serialize(&unsigned).unwrap();
+        data.push(sighash.to_byte());
+        if sighash != SigHash::All {
+            data.extend((index as u32).to_le_bytes());
+        }
+        Some(data)
+    }
+
+    // address input `index` spends from, see Script::signer_address.
+    // the key of a compact P2PKH unlock is recovered from its signature.
+    pub fn signer_address(&self, index: usize) -> Option<String> {
+        let unlock = &self.inputs.get(index)?.unlock_script;
+        let ctx = ScriptContext {
+            tx: self,
+            input_index: index,
+        };
+        match unlock.expand_compact(&ctx) {
+            Some(expanded) => expanded.signer_address(),
+            None => unlock.signer_address(),
+        }
+    }
+
+    // hash of the signing data: signing doesn't change it, any other change to the trx does
+    pub fn tx_id(&self) -> String {
+        sha256::digest(self.signing_data())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Transaction {
     pub trx_id: String,
-    pub data: Vec<u8>,
-    pub signature: Option<Signature>,
+    pub data: Vec<u8>, // encoded TransactionData
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TxValidationErr {
+    MissingSignature, // input without unlocking script
+    InvalidData,
+    MissingTxId,
+    InvalidTxId, // not the hash of the trx
+    DuplicateTxId,
+    NoInputs,
+    NoOutputs,
+    MissingInput(OutPoint),
+    NotFinal,              // lock time not reached yet
+    InputLocked(OutPoint), // relative lock of the input not reached yet
+    DoubleSpend(OutPoint),
+    InvalidScript(OutPoint, ScriptErr), // unlocking script doesn't satisfy the spent output
+    InsufficientInputs,
+    ValueOverflow,
+    UnexpectedCoinbase,
+    MemPoolFull,          // fee rate too low to stay in a full mem_pool
+    ReplacementFeeTooLow, // doesn't pay more than the pending trxs it replaces
+}
+
+impl Transaction {
+    pub fn tx_data(&self) -> Result<TransactionData, TxValidationErr> {
+        bincode::deserialize(&self.data).map_err(|_| TxValidationErr::InvalidData)
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_data().is_ok_and(|data| data.is_coinbase())
+    }
+
+    // encoded bytes, as the trx takes space in a block
+    pub fn size(&self) -> usize {
+        bincode::serialized_size(self).unwrap_or(u64::MAX) as usize
+    }
+
+    // hash of the whole encoded trx, unlocking scripts included: unlike the id,
+    // it changes when the trx is signed or its signatures are swapped
+    pub fn witness_id(&self) -> String {
+        sha256::digest(&self.data[..])
+    }
+
+    pub fn compute_id(&self) -> Result<String, TxValidationErr> {
+        Ok(self.tx_data()?.tx_id())
+    }
+
+    pub fn verify_id(&self) -> Result<(), TxValidationErr> {
+        if self.trx_id != self.compute_id()? {
+            return Err(TxValidationErr::InvalidTxId);
+        }
+        Ok(())
+    }
+
+    // `trx_id` is the trx's own and every input has unlocking data.
+    // the scripts need the outputs spent, they're run by UtxoSet::check_tx.
+    pub fn verify(&self) -> Result<(), TxValidationErr> {
+        self.verify_id()?;
+        let data = self.tx_data()?;
+        if data
+            .inputs
+            .iter()
+            .any(|input| input.unlock_script.0.is_empty())
+        {
+            return Err(TxValidationErr::MissingSignature);
+        }
+        Ok(())
+    }
 }
 
 impl Encoder for Transaction {
@@ -41,67 +284,115 @@ impl Decoder for Transaction {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum TxBuilderErr {
     RequiredInputs,
     RequiredOutputs,
+    InvalidAddress(String),
     SerializeFail,
     SignTxFail,
 }
 pub type TxBuilderResult = Result<Transaction, TxBuilderErr>;
 
+#[derive(Default)]
 pub struct TxBuilder {
-    sender_addr: String,
-    receiver_addr: String,
-    value: f64,
-    inputs: Option<Vec<Transaction>>,
-    outputs: Option<Vec<Transaction>>,
-    tx_id: Option<String>,
+    inputs: Vec<TxInput>,
+    outputs: Vec<TxOutput>,
+    lock_time: Option<LockTime>,
+    replaceable: bool,
+    coinbase_height: Option<u64>,
+    invalid_addr: Option<String>,
 }
 
 impl TxBuilder {
-    pub fn new(sender_addr: String, receiver_addr: String, value: f64) -> Self {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // reward of the block at `height`, it spends nothing and isn't signed.
+    // the reward goes to the outputs added next.
+    pub fn coinbase(height: u64) -> Self {
         Self {
-            sender_addr,
-            receiver_addr,
-            value,
-            inputs: None,
-            outputs: None,
-            tx_id: None,
+            coinbase_height: Some(height),
+            ..Self::default()
+        }
+    }
+
+    pub fn input(mut self, prev_out: OutPoint) -> Self {
+        self.inputs.push(TxInput::new(prev_out));
+        self
+    }
+
+    // spendable `blocks` blocks after `prev_out` was confirmed
+    pub fn locked_input(mut self, prev_out: OutPoint, blocks: u64) -> Self {
+        let mut input = TxInput::new(prev_out);
+        input.relative_lock = Some(blocks);
+        self.inputs.push(input);
+        self
+    }
+
+    pub fn inputs(self, prev_outs: Vec<OutPoint>) -> Self {
+        prev_outs
+            .into_iter()
+            .fold(self, |builder, prev_out| builder.input(prev_out))
+    }
+
+    // pays `recipient_addr` (P2PKH or P2SH), a malformed address fails the build
+    pub fn output(self, recipient_addr: String, value: Amount) -> Self {
+        match Script::from_address(&recipient_addr) {
+            Some(lock_script) => self.output_script(lock_script, value),
+            None => Self {
+                invalid_addr: Some(recipient_addr),
+                ..self
+            },
         }
     }
 
-    pub fn inputs(mut self, inputs: Vec<Transaction>) -> Self {
-        self.inputs = Some(inputs);
+    pub fn output_script(mut self, lock_script: Script, value: Amount) -> Self {
+        self.outputs.push(TxOutput { value, lock_script });
+        self
+    }
+
+    pub fn outputs(mut self, outputs: Vec<TxOutput>) -> Self {
+        self.outputs.extend(outputs);
+        self
+    }
+
+    pub fn lock_time(mut self, lock_time: LockTime) -> Self {
+        self.lock_time = Some(lock_time);
         self
     }
 
-    pub fn outputs(mut self, outputs: Vec<Transaction>) -> Self {
-        self.outputs = Some(outputs);
+    // can be replaced while pending, see MemPool::replace
+    pub fn replaceable(mut self) -> Self {
+        self.replaceable = true;
         self
     }
 
+    // unsigned, see Wallet::sign_transaction
     pub fn build(self) -> Result<Transaction, TxBuilderErr> {
-        if self.inputs.is_none() {
+        if let Some(addr) = self.invalid_addr {
+            return Err(TxBuilderErr::InvalidAddress(addr));
+        }
+        if self.inputs.is_empty() && self.coinbase_height.is_none() {
             return Err(TxBuilderErr::RequiredInputs);
         }
-        if self.outputs.is_none() {
+        if self.outputs.is_empty() {
             return Err(TxBuilderErr::RequiredOutputs);
         }
 
         let tx_data = TransactionData {
-            sender_addr: self.sender_addr,
-            receiver_addr: self.receiver_addr,
-            value: self.value,
-            inputs: self.inputs.unwrap(),
-            outputs: self.outputs.unwrap(),
+            inputs: self.inputs,
+            outputs: self.outputs,
+            lock_time: self.lock_time,
+            replaceable: self.replaceable,
+            coinbase_height: self.coinbase_height,
         };
         let encoded_tx_data =
             bincode::serialize(&tx_data).map_err(|_| TxBuilderErr::SerializeFail)?;
         Ok(Transaction {
-            trx_id: String::new(),
+            trx_id: tx_data.tx_id(),
             data: encoded_tx_data,
-            signature: None,
         })
     }
 }
@@ -0,0 +1,184 @@
+use super::amount::Amount;
+use super::block::Block;
+use super::script::{verify_script, ScriptContext};
+use super::transaction::{OutPoint, Transaction, TxOutput, TxValidationErr};
+use std::collections::{HashMap, HashSet};
+
+// Unspent outputs of the active chain
+// - a trx spends whole outputs (`inputs`), each unlocked by satisfying its locking script
+// - output `i` of a trx is OutPoint { tx_id: trx_id, index: i }
+// - whatever the inputs hold on top of the outputs is the fee
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoEntry {
+    pub output: TxOutput,
+    pub height: u64, // block that created the output
+}
+
+// what connecting a block changed, used to disconnect it again
+#[derive(Debug, Default)]
+pub struct BlockUndo {
+    spent: Vec<(OutPoint, UtxoEntry)>,
+    created: Vec<OutPoint>,
+}
+
+#[derive(Debug, Default)]
+pub struct UtxoSet {
+    entries: HashMap<OutPoint, UtxoEntry>,
+}
+
+impl UtxoSet {
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UtxoEntry> {
+        self.entries.get(outpoint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn unspent_of(&self, addr: &str) -> Vec<(OutPoint, UtxoEntry)> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.output.pays_to(addr))
+            .map(|(outpoint, entry)| (outpoint.clone(), entry.clone()))
+            .collect()
+    }
+
+    // can't be more than the supply, which fits an Amount
+    pub fn balance_of(&self, addr: &str) -> Amount {
+        Amount::checked_sum(
+            self.unspent_of(addr)
+                .iter()
+                .map(|(_, entry)| entry.output.value),
+        )
+        .unwrap_or(Amount::MAX)
+    }
+
+    // check the spends of a non-coinbase `tx` included in the block at `height`,
+    // `spent` are outpoints already taken by other trxs.
+    // every unlocking script is run against the locking script of the output it spends.
+    // returns the fee of `tx`.
+    pub fn check_tx(
+        &self,
+        tx: &Transaction,
+        height: u64,
+        spent: &HashSet<OutPoint>,
+    ) -> Result<Amount, TxValidationErr> {
+        if tx.trx_id.is_empty() {
+            return Err(TxValidationErr::MissingTxId);
+        }
+        let data = tx.tx_data()?;
+        if data.is_coinbase() {
+            return Err(TxValidationErr::UnexpectedCoinbase);
+        }
+        if data.inputs.is_empty() {
+            return Err(TxValidationErr::NoInputs);
+        }
+        if data.outputs.is_empty() {
+            return Err(TxValidationErr::NoOutputs);
+        }
+
+        let mut input_value = Amount::ZERO;
+        let mut seen = HashSet::new();
+        for (input_index, input) in data.inputs.iter().enumerate() {
+            let outpoint = &input.prev_out;
+            if spent.contains(outpoint) || !seen.insert(outpoint) {
+                return Err(TxValidationErr::DoubleSpend(outpoint.clone()));
+            }
+            let entry = self
+                .get(outpoint)
+                .ok_or_else(|| TxValidationErr::MissingInput(outpoint.clone()))?;
+            if !input.is_unlocked(entry.height, height) {
+                return Err(TxValidationErr::InputLocked(outpoint.clone()));
+            }
+            let ctx = ScriptContext {
+                tx: &data,
+                input_index,
+            };
+            verify_script(&input.unlock_script, &entry.output.lock_script, &ctx)
+                .map_err(|err| TxValidationErr::InvalidScript(outpoint.clone(), err))?;
+            input_value = input_value
+                .checked_add(entry.output.value)
+                .ok_or(TxValidationErr::ValueOverflow)?;
+        }
+
+        let output_value = data.output_value().ok_or(TxValidationErr::ValueOverflow)?;
+        input_value
+            .checked_sub(output_value)
+            .ok_or(TxValidationErr::InsufficientInputs)
+    }
+
+    // returns the fee of `tx`, nothing for a coinbase
+    fn apply_tx(
+        &mut self,
+        tx: &Transaction,
+        height: u64,
+        undo: &mut BlockUndo,
+    ) -> Result<Amount, TxValidationErr> {
+        let data = tx.tx_data()?;
+        let fee = match data.is_coinbase() {
+            true => Amount::ZERO,
+            false => self.check_tx(tx, height, &HashSet::new())?,
+        };
+
+        let first = OutPoint {
+            tx_id: tx.trx_id.clone(),
+            index: 0,
+        };
+        if tx.trx_id.is_empty() || self.entries.contains_key(&first) {
+            return Err(TxValidationErr::DuplicateTxId);
+        }
+
+        for input in data.inputs {
+            let entry = self.entries.remove(&input.prev_out).unwrap();
+            undo.spent.push((input.prev_out, entry));
+        }
+        for (index, output) in data.outputs.into_iter().enumerate() {
+            let outpoint = OutPoint {
+                tx_id: tx.trx_id.clone(),
+                index: index as u32,
+            };
+            self.entries
+                .insert(outpoint.clone(), UtxoEntry { output, height });
+            undo.created.push(outpoint);
+        }
+        Ok(fee)
+    }
+
+    // connect `b` on top of the set, all or nothing.
+    // fails with the index of the first trx spending something it can't.
+    // returns the fees of its trxs along with the undo data.
+    pub fn apply_block(
+        &mut self,
+        b: &Block,
+    ) -> Result<(BlockUndo, Amount), (usize, TxValidationErr)> {
+        let mut undo = BlockUndo::default();
+        let mut fees = Amount::ZERO;
+        for (index, tx) in b.transactions.iter().enumerate() {
+            let fee = self
+                .apply_tx(tx, b.height(), &mut undo)
+                .and_then(|fee| fees.checked_add(fee).ok_or(TxValidationErr::ValueOverflow));
+            match fee {
+                Ok(total) => fees = total,
+                Err(err) => {
+                    self.undo_block(undo);
+                    return Err((index, err));
+                }
+            }
+        }
+        Ok((undo, fees))
+    }
+
+    pub fn undo_block(&mut self, undo: BlockUndo) {
+        for outpoint in undo.created.iter().rev() {
+            self.entries.remove(outpoint);
+        }
+        for (outpoint, entry) in undo.spent.into_iter().rev() {
+            self.entries.insert(outpoint, entry);
+        }
+    }
+}
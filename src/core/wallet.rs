@@ -1,78 +1,707 @@
-use super::cyphers::{PrivateKey, PublicKey, Signature};
-use super::transaction::{TxBuilder, TxBuilderResult};
+use super::amount::Amount;
+use super::cyphers::{PrivateKey, PublicKey, RecoverableSignature, Signature};
+use super::hd::{DerivationPath, ExtendedPrivateKey, ExtendedPublicKey, HdErr};
+use super::keystore::{Keystore, KeystoreErr, Secret};
+use super::mem_pool::MemPoolEntry;
+use super::mnemonic::{Mnemonic, MnemonicErr};
+use super::psbt::Psbt;
+use super::script::{decode_sig, OpCode, Script};
+use super::transaction::{
+    OutPoint, SigHash, Transaction, TxBuilder, TxBuilderErr, TxBuilderResult, TxInput, TxOutput,
+};
+use super::utxo::UtxoEntry;
 use k256::ecdsa::Error as EcdsaErr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+// BIP44 account 0, then 0/i for receive and 1/i for change addresses
+pub const ACCOUNT_PATH: &str = "m/44'/0'/0'";
+const RECEIVE: u32 = 0;
+const CHANGE: u32 = 1;
+// unused addresses in a row that end the search of a chain, see `discover`
+pub const GAP_LIMIT: u32 = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum CreateTxErr {
+    InsufficientFunds { needed: Amount, available: Amount }, // needed: value + fee, or the bumped fee
+    ValueOverflow,
+    Build(TxBuilderErr),
+    SignFail,
+}
 
-#[derive(Debug)]
 pub struct Wallet {
     data: Vec<u8>,
 
     pub address: String,
 
-    private_key: PrivateKey,
+    private_key: Option<PrivateKey>, // None while locked
     public_key: PublicKey,
+    keystore: Option<PathBuf>, // None for an in memory wallet
+
+    // HD wallet: the account key (ACCOUNT_PATH), receive and change keys derive from it
+    account: Option<ExtendedPrivateKey>,
+    next_index: [u32; 2],               // next fresh receive and change index
+    derived: Vec<(String, PrivateKey)>, // every address handed out but its own, with its key
+    mnemonic: Option<Mnemonic>,         // the seed phrase it was created from, for backup
+}
+
+// what's public only: no key, chain code nor seed phrase
+impl fmt::Debug for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Wallet")
+            .field("address", &self.address)
+            .field("public_key", &self.public_key)
+            .field("locked", &self.is_locked())
+            .field("keystore", &self.keystore)
+            .field("hd", &self.account.is_some())
+            .field("next_index", &self.next_index)
+            .field("addresses", &self.addresses())
+            .finish_non_exhaustive()
+    }
 }
 
 impl Wallet {
+    // in memory wallet with a fresh key, lost when dropped
     pub fn new(data: Vec<u8>) -> Result<Self, String> {
         let private_key = PrivateKey::generate()?;
+        Ok(Self {
+            data,
+            ..Self::with_key(private_key)
+        })
+    }
+
+    fn with_key(private_key: PrivateKey) -> Self {
         let public_key = private_key.public_key();
+        Self {
+            data: vec![],
+            address: public_key.address(),
+            private_key: Some(private_key),
+            public_key,
+            keystore: None,
+            account: None,
+            next_index: [0, 0],
+            derived: vec![],
+            mnemonic: None,
+        }
+    }
+
+    // wallet with a fresh key, saved to `path` encrypted under `passphrase`. it's unlocked.
+    // an existing keystore at `path` is never overwritten.
+    pub fn create<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self, KeystoreErr> {
+        let private_key = PrivateKey::generate().map_err(KeystoreErr::Encode)?;
+        let mut wallet = Self::with_key(private_key);
+        wallet.save(path, passphrase)?;
+        Ok(wallet)
+    }
+
+    // save the key, or the account key and seed phrase of an HD wallet, to a new keystore
+    // at `path` encrypted under `passphrase`: from then on it can be locked and unlocked.
+    // an existing keystore at `path` is never overwritten.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P, passphrase: &str) -> Result<(), KeystoreErr> {
+        let secret = self.secret().ok_or(KeystoreErr::Locked)?;
+        Keystore::encrypt(&secret, passphrase)?.create(&path)?;
+        self.keystore = Some(path.as_ref().to_path_buf());
+        Ok(())
+    }
 
-        // generate address: !keep simple! -- first 20 of public bytes
-        // address can follow bitcoin or ETH : will be implemented
-        let address = hex::encode(&public_key.as_bytes()[0..20]);
+    fn secret(&self) -> Option<Secret> {
+        match &self.account {
+            Some(account) => Some(Secret::Account {
+                account: account.clone(),
+                mnemonic: self.mnemonic.clone(),
+            }),
+            None => self.private_key.clone().map(Secret::Key),
+        }
+    }
+
+    // wallet saved to `path`, locked: it knows its address but can't sign until unlocked.
+    // an HD wallet only knows its first address until then.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, KeystoreErr> {
+        let keystore = Keystore::read(&path)?;
+        let public_key = keystore.public_key().clone();
         Ok(Self {
-            data,
-            address,
-            private_key,
+            data: vec![],
+            address: public_key.address(),
+            private_key: None,
             public_key,
+            keystore: Some(path.as_ref().to_path_buf()),
+            account: None,
+            next_index: [0, 0],
+            derived: vec![],
+            mnemonic: None,
+        })
+    }
+
+    // HD wallet: every key derives from `seed`, backing up the seed backs up all of them.
+    // its own address is the first receive address.
+    pub fn from_seed(seed: &[u8]) -> Result<Self, HdErr> {
+        let account = ExtendedPrivateKey::from_seed(seed)?.derive_path(&ACCOUNT_PATH.parse()?)?;
+        let first = first_receive_key(&account)?;
+        Ok(Self {
+            account: Some(account),
+            next_index: [1, 0],
+            ..Self::with_key(first)
+        })
+    }
+
+    // HD wallet from a fresh seed phrase of `word_count` words, see `mnemonic` to back it up.
+    // `passphrase` (can be empty) is needed along with the phrase to restore it.
+    pub fn generate_mnemonic(word_count: usize, passphrase: &str) -> Result<Self, MnemonicErr> {
+        let mnemonic = Mnemonic::generate(word_count)?;
+        Self::with_mnemonic(mnemonic, passphrase)
+    }
+
+    // restore the HD wallet of a seed phrase: the same phrase and passphrase
+    // always give the same keys and addresses
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Self, MnemonicErr> {
+        Self::with_mnemonic(Mnemonic::parse(phrase)?, passphrase)
+    }
+
+    fn with_mnemonic(mnemonic: Mnemonic, passphrase: &str) -> Result<Self, MnemonicErr> {
+        let wallet = Self::from_seed(mnemonic.to_seed(passphrase).as_ref())?;
+        Ok(Self {
+            mnemonic: Some(mnemonic),
+            ..wallet
         })
     }
 
-    // TODO: creating transaction
-    // - request blockchain history tx of given wallet's address (UTXO input)
-    // - compute trx outputs
-    // - inject inputs, outputs when creating tx
-    // - sign tx
-    // ### DONE ### creating transaction
-    pub fn create_transaction(&self, receiver_addr: String, value: f64) -> TxBuilderResult {
-        TxBuilder::new(self.address.clone(), receiver_addr, value)
-            .inputs(vec![])
-            .outputs(vec![])
-            .build()
+    // the seed phrase to write down, if the wallet was created from one
+    pub fn mnemonic(&self) -> Option<Zeroizing<String>> {
+        self.mnemonic.as_ref().map(|mnemonic| mnemonic.phrase())
+    }
+
+    // what a watch-only party derives the receive (0/i) and change (1/i) addresses from
+    pub fn account_xpub(&self) -> Option<ExtendedPublicKey> {
+        self.account.as_ref().map(|account| account.to_public())
+    }
+
+    // wallet of the receive or change key `index` of an HD wallet, to sign what it was paid
+    pub fn derive(&self, change: bool, index: u32) -> Option<Wallet> {
+        let chain = if change { CHANGE } else { RECEIVE };
+        let key = self
+            .account
+            .as_ref()?
+            .derive_path(&DerivationPath(vec![chain, index]))
+            .ok()?;
+        Some(Self::with_key(key.private_key().clone()))
+    }
+
+    // next unused receive or change address of an HD wallet
+    pub fn fresh_address(&mut self, change: bool) -> Option<String> {
+        let key = self.next_key(change)?;
+        Some(self.use_key(change, key))
+    }
+
+    // key of the address `fresh_address` hands out next, not used up yet
+    fn next_key(&self, change: bool) -> Option<PrivateKey> {
+        let index = self.next_index[change as usize];
+        self.derive(change, index)?.private_key
+    }
+
+    fn use_key(&mut self, change: bool, key: PrivateKey) -> String {
+        let address = key.public_key().address();
+        self.derived.push((address.clone(), key));
+        self.next_index[change as usize] += 1;
+        address
+    }
+
+    // after a restore, hand out again the receive and change addresses that were paid
+    // (`used`, see BlockChain::is_used) so their coins are found and spendable. addresses are
+    // handed out in order: a chain is searched until GAP_LIMIT unused ones in a row.
+    // returns how many used addresses were found.
+    pub fn discover<F: Fn(&str) -> bool>(&mut self, used: F) -> usize {
+        let mut found = 0;
+        for change in [false, true] {
+            let mut index = self.next_index[change as usize];
+            let mut gap = 0;
+            while gap < GAP_LIMIT {
+                let Some(wallet) = self.derive(change, index) else {
+                    break;
+                };
+                if used(&wallet.address) {
+                    while self.next_index[change as usize] <= index {
+                        self.fresh_address(change);
+                    }
+                    found += 1;
+                    gap = 0;
+                } else {
+                    gap += 1;
+                }
+                index += 1;
+            }
+        }
+        found
+    }
+
+    // every address the wallet can spend from: its own, then the ones it handed out
+    pub fn addresses(&self) -> Vec<String> {
+        let derived = self.derived.iter().map(|(address, _)| address.clone());
+        std::iter::once(self.address.clone())
+            .chain(derived)
+            .collect()
+    }
+
+    // key unlocking what's paid to `address`, None if it's not the wallet's or it's locked
+    fn key_of(&self, address: &str) -> Option<&PrivateKey> {
+        if address == self.address {
+            return self.private_key.as_ref();
+        }
+        let derived = self.derived.iter().find(|(derived, _)| derived == address);
+        derived.map(|(_, key)| key)
+    }
+
+    // the wallet's key then the derived ones, none while locked
+    fn keys(&self) -> impl Iterator<Item = &PrivateKey> {
+        let derived = self.derived.iter().map(|(_, private_key)| private_key);
+        self.private_key.iter().chain(derived)
+    }
+
+    fn owner_of(&self, output: &TxOutput) -> Option<String> {
+        self.addresses()
+            .into_iter()
+            .find(|address| output.pays_to(address))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.private_key.is_none()
+    }
+
+    // decrypt the key of the keystore, signing works until `lock`.
+    // an HD wallet derives again the keys of the addresses it handed out.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), KeystoreErr> {
+        let path = self.keystore.as_ref().ok_or(KeystoreErr::NotPersisted)?;
+        let (private_key, account, mnemonic) = match Keystore::read(path)?.decrypt(passphrase)? {
+            Secret::Key(private_key) => (private_key, None, None),
+            Secret::Account { account, mnemonic } => {
+                let first = first_receive_key(&account).map_err(|_| KeystoreErr::InvalidKey)?;
+                (first, Some(account), mnemonic)
+            }
+        };
+        if private_key.public_key() != self.public_key {
+            return Err(KeystoreErr::InvalidKey);
+        }
+        self.private_key = Some(private_key);
+        if account.is_none() {
+            return Ok(());
+        }
+
+        self.account = account;
+        self.mnemonic = mnemonic;
+        let handed_out = [self.next_index[0].max(1), self.next_index[1]];
+        self.next_index = [1, 0];
+        self.derived.clear();
+        for change in [false, true] {
+            while self.next_index[change as usize] < handed_out[change as usize] {
+                self.fresh_address(change);
+            }
+        }
+        Ok(())
+    }
+
+    // forget the keys, they're zeroized on drop: the account key and every derived one of an
+    // HD wallet too. only a saved wallet can be unlocked again.
+    pub fn lock(&mut self) -> Result<(), KeystoreErr> {
+        if self.keystore.is_none() {
+            return Err(KeystoreErr::NotPersisted);
+        }
+        self.private_key = None;
+        self.account = None;
+        self.derived.clear();
+        self.mnemonic = None;
+        Ok(())
+    }
+
+    // encrypt the key under `new` instead of `old`, locked or not
+    pub fn change_passphrase(&self, old: &str, new: &str) -> Result<(), KeystoreErr> {
+        let path = self.keystore.as_ref().ok_or(KeystoreErr::NotPersisted)?;
+        let secret = Keystore::read(path)?.decrypt(old)?;
+        Keystore::encrypt(&secret, new)?.write(path)
+    }
+
+    // spends `inputs` (owned by this wallet): `value` to `receiver_addr`, `change` back to the wallet,
+    // at the next change address (1/i) of an HD wallet. both outputs are P2PKH, the trx can be
+    // replaced by a fee bump (see `bump_fee`). the rest of the inputs is left to the miner as fee.
+    pub fn build_transaction(
+        &mut self,
+        inputs: Vec<OutPoint>,
+        receiver_addr: String,
+        value: Amount,
+        change: Amount,
+    ) -> TxBuilderResult {
+        let change_key = self.next_key(true);
+        let tx = self.build_paying(inputs, receiver_addr, value, change, change_key.as_ref())?;
+        if let Some(key) = change_key.filter(|_| !change.is_zero()) {
+            self.use_key(true, key);
+        }
+        Ok(tx)
+    }
+
+    // change goes to `change_key`, or the wallet's own address without one
+    fn build_paying(
+        &self,
+        inputs: Vec<OutPoint>,
+        receiver_addr: String,
+        value: Amount,
+        change: Amount,
+        change_key: Option<&PrivateKey>,
+    ) -> TxBuilderResult {
+        let mut builder = TxBuilder::new()
+            .inputs(inputs)
+            .output(receiver_addr, value)
+            .replaceable();
+        if !change.is_zero() {
+            let change_addr = match change_key {
+                Some(key) => key.public_key().address(),
+                None => self.address.clone(),
+            };
+            builder = builder.output(change_addr, change);
+        }
+        builder.build()
+    }
+
+    // signed trx paying `value` to `receiver_addr` out of `unspent`, the coins of any of `addresses`
+    // (see BlockChain::spendable_of).
+    // - coins are selected largest first until they cover `value` and the fee
+    // - each input is signed with the key of the address it spends
+    // - fee = encoded size of the signed trx * `fee_rate` (per byte), compact signatures have a
+    //   fixed size so it's known before signing
+    // - what's left goes back to the wallet as change, see `build_transaction`
+    pub fn create_transaction(
+        &mut self,
+        unspent: Vec<(OutPoint, UtxoEntry)>,
+        receiver_addr: String,
+        value: Amount,
+        fee_rate: Amount,
+    ) -> Result<Transaction, CreateTxErr> {
+        let mut coins: Vec<(OutPoint, Amount, String)> = unspent
+            .into_iter()
+            .filter_map(|(outpoint, entry)| {
+                let owner = self.owner_of(&entry.output)?;
+                Some((outpoint, entry.output.value, owner))
+            })
+            .collect();
+        coins.sort_by(|(a, a_value, _), (b, b_value, _)| {
+            b_value
+                .cmp(a_value)
+                .then_with(|| (&a.tx_id, a.index).cmp(&(&b.tx_id, b.index)))
+        });
+        let available = Amount::checked_sum(coins.iter().map(|(_, value, _)| *value))
+            .ok_or(CreateTxErr::ValueOverflow)?;
+
+        // the fee depends on the size, which depends on the inputs selected for the fee
+        let change_key = self.next_key(true);
+        let mut fee = Amount::ZERO;
+        loop {
+            let needed = value.checked_add(fee).ok_or(CreateTxErr::ValueOverflow)?;
+            let mut inputs = vec![];
+            let mut owners = vec![];
+            let mut selected = Amount::ZERO;
+            for (outpoint, value, owner) in &coins {
+                if selected >= needed {
+                    break;
+                }
+                inputs.push(outpoint.clone());
+                owners.push(owner.as_str());
+                selected = selected.checked_add(*value).unwrap(); // at most `available`
+            }
+            if selected < needed {
+                return Err(CreateTxErr::InsufficientFunds { needed, available });
+            }
+
+            let change = selected.checked_sub(needed).unwrap();
+            let mut tx = self
+                .build_paying(
+                    inputs,
+                    receiver_addr.clone(),
+                    value,
+                    change,
+                    change_key.as_ref(),
+                )
+                .map_err(CreateTxErr::Build)?;
+            self.sign_owned(&mut tx, &owners)
+                .map_err(|_| CreateTxErr::SignFail)?;
+
+            let required = fee_rate
+                .checked_mul(tx.size() as u64)
+                .ok_or(CreateTxErr::ValueOverflow)?;
+            if required <= fee {
+                if let Some(key) = change_key.filter(|_| !change.is_zero()) {
+                    self.use_key(true, key);
+                }
+                return Ok(tx);
+            }
+            fee = required;
+        }
+    }
+
+    // replacement of `pending`, a trx of this wallet, paying at least `fee_rate` per byte.
+    // the fee comes out of the change output. to replace `pending` it pays a higher fee
+    // and fee rate than it did, see MemPool::replace.
+    pub fn bump_fee(
+        &self,
+        pending: &MemPoolEntry,
+        fee_rate: Amount,
+    ) -> Result<Transaction, CreateTxErr> {
+        let data = pending.tx.tx_data().map_err(|_| CreateTxErr::SignFail)?;
+        let change_index = data
+            .outputs
+            .iter()
+            .rposition(|output| self.owner_of(output).is_some());
+        // signed again by the keys that signed it
+        let owners = (0..data.inputs.len())
+            .map(|index| data.signer_address(index).ok_or(CreateTxErr::SignFail))
+            .collect::<Result<Vec<_>, _>>()?;
+        let owners: Vec<&str> = owners.iter().map(String::as_str).collect();
+        let change = change_index
+            .map(|index| data.outputs[index].value)
+            .unwrap_or(Amount::ZERO);
+        // what the fee can grow to: the current one plus the whole change
+        let available = pending
+            .fee
+            .checked_add(change)
+            .ok_or(CreateTxErr::ValueOverflow)?;
+
+        let mut fee = pending.fee;
+        loop {
+            let mut bumped = data.clone();
+            let new_change = available
+                .checked_sub(fee)
+                .ok_or(CreateTxErr::InsufficientFunds {
+                    needed: fee,
+                    available,
+                })?;
+            match change_index {
+                Some(index) if new_change.is_zero() => {
+                    bumped.outputs.remove(index);
+                }
+                Some(index) => bumped.outputs[index].value = new_change,
+                None => {}
+            }
+            if bumped.outputs.is_empty() {
+                return Err(CreateTxErr::Build(TxBuilderErr::RequiredOutputs));
+            }
+            bumped.replaceable = true;
+            let mut tx = Transaction {
+                trx_id: bumped.tx_id(),
+                data: bincode::serialize(&bumped).map_err(|_| CreateTxErr::SignFail)?,
+            };
+            self.sign_owned(&mut tx, &owners)
+                .map_err(|_| CreateTxErr::SignFail)?;
+
+            // above the fee and the fee rate of `pending` too, whatever the new size
+            let size = tx.size() as u64;
+            let same_rate = pending.fee.units() as u128 * size as u128 / pending.size as u128;
+            let above_pending = (same_rate as u64).max(pending.fee.units()) + 1;
+            let required = fee_rate
+                .checked_mul(size)
+                .ok_or(CreateTxErr::ValueOverflow)?
+                .max(Amount::from_units(above_pending));
+            if required <= fee {
+                return Ok(tx);
+            }
+            fee = required;
+        }
     }
 
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    // fails while the wallet is locked
     pub fn sign_data(&self, data: &[u8]) -> Result<Signature, EcdsaErr> {
-        self.private_key.sign(&data)
+        let private_key = self.private_key.as_ref().ok_or_else(EcdsaErr::new)?;
+        private_key.sign(data)
+    }
+
+    pub fn sign_recoverable(&self, data: &[u8]) -> Result<RecoverableSignature, EcdsaErr> {
+        let private_key = self.private_key.as_ref().ok_or_else(EcdsaErr::new)?;
+        private_key.sign_recoverable(data)
+    }
+
+    // unlock every input of `tx` as a P2PKH output of this wallet, committing to the whole trx
+    pub fn sign_transaction(&self, tx: &mut Transaction) -> Result<(), EcdsaErr> {
+        let inputs = tx.tx_data().map_err(|_| EcdsaErr::new())?.inputs.len();
+        (0..inputs).try_for_each(|index| self.sign_input(tx, index, SigHash::All))
+    }
+
+    // unlock input `index` of `tx` as a P2PKH output of this wallet, with a compact
+    // recoverable signature: no public key to carry. it only commits to what `sighash` covers.
+    pub fn sign_input(
+        &self,
+        tx: &mut Transaction,
+        index: usize,
+        sighash: SigHash,
+    ) -> Result<(), EcdsaErr> {
+        let private_key = self.private_key.as_ref().ok_or_else(EcdsaErr::new)?;
+        sign_input_with(private_key, tx, index, sighash)
+    }
+
+    // unlock input i of `tx` as an output paid to `owners[i]`, any address of the wallet
+    fn sign_owned(&self, tx: &mut Transaction, owners: &[&str]) -> Result<(), EcdsaErr> {
+        owners.iter().enumerate().try_for_each(|(index, owner)| {
+            let private_key = self.key_of(owner).ok_or_else(EcdsaErr::new)?;
+            sign_input_with(private_key, tx, index, SigHash::All)
+        })
+    }
+
+    // crowdfunding: add `prev_out`, an output of this wallet, as an input of `tx`.
+    // its signature only covers itself and the outputs, so anyone can add theirs after.
+    pub fn contribute(&self, tx: &mut Transaction, prev_out: OutPoint) -> Result<(), EcdsaErr> {
+        let mut data = tx.tx_data().map_err(|_| EcdsaErr::new())?;
+        data.inputs.push(TxInput::new(prev_out));
+        tx.data = bincode::serialize(&data).map_err(|_| EcdsaErr::new())?;
+        tx.trx_id = data.tx_id();
+        self.sign_input(tx, data.inputs.len() - 1, SigHash::AllAnyoneCanPay)
+    }
+
+    // sign every input of `psbt` one of this wallet's keys can unlock, no chain needed:
+    // the key of the address a P2PKH input spends, any of them for a P2SH one.
+    // returns how many inputs were signed.
+    pub fn sign_psbt(&self, psbt: &mut Psbt) -> Result<usize, EcdsaErr> {
+        if self.is_locked() {
+            return Err(EcdsaErr::new());
+        }
+        let mut signed = 0;
+        for index in 0..psbt.inputs.len() {
+            let Some(signed_data) = psbt.signature_hash(index, SigHash::All) else {
+                continue;
+            };
+            let owner = psbt.inputs[index].spent_output.recipient_addr();
+            let keys: Vec<&PrivateKey> = match owner.and_then(|owner| self.key_of(&owner)) {
+                Some(private_key) => vec![private_key],
+                None => self.keys().collect(),
+            };
+            let mut added = false;
+            for private_key in keys {
+                let signature = private_key.sign(&signed_data)?;
+                let public_key = private_key.public_key();
+                added |= psbt
+                    .add_signature(index, public_key, signature, SigHash::All)
+                    .is_ok();
+            }
+            if added {
+                signed += 1;
+            }
+        }
+        Ok(signed)
     }
+
+    // add this wallet's signature to input `index` of `tx`, which spends a P2SH output of
+    // `redeem_script` (see Script::multisig). the other signers add theirs the same way,
+    // in any order; the input is unlocked once enough of them did.
+    pub fn sign_multisig(
+        &self,
+        tx: &mut Transaction,
+        index: usize,
+        redeem_script: &Script,
+    ) -> Result<(), EcdsaErr> {
+        let (_, keys) = redeem_script.multisig_keys().ok_or_else(EcdsaErr::new)?;
+        let keys = keys
+            .into_iter()
+            .map(PublicKey::from_sec1)
+            .collect::<Result<Vec<_>, _>>()?;
+        let own_index = keys
+            .iter()
+            .position(|key| *key == self.public_key)
+            .ok_or_else(EcdsaErr::new)?;
+
+        let mut data = tx.tx_data().map_err(|_| EcdsaErr::new())?;
+        let signed_data = data
+            .signature_hash(index, SigHash::All)
+            .ok_or_else(EcdsaErr::new)?;
+
+        // signatures already there, by key index. the unlocking script ends with the redeem script
+        let mut signatures: Vec<(usize, (Signature, SigHash))> = vec![];
+        let pushed = data.inputs[index].unlock_script.0.iter().rev().skip(1);
+        for op in pushed {
+            let OpCode::Push(bytes) = op else { continue };
+            let Some((signature, sighash)) = decode_sig(bytes) else {
+                continue;
+            };
+            let Some(their_data) = data.signature_hash(index, sighash) else {
+                continue;
+            };
+            let signer = keys
+                .iter()
+                .position(|key| key.verify(&signature, &their_data).is_ok());
+            if let Some(key_index) = signer.filter(|key_index| *key_index != own_index) {
+                signatures.push((key_index, (signature, sighash)));
+            }
+        }
+        let signature = self.sign_data(&signed_data)?;
+        signatures.push((own_index, (signature, SigHash::All)));
+        signatures.sort_by_key(|(key_index, _)| *key_index);
+
+        let signatures: Vec<(Signature, SigHash)> =
+            signatures.into_iter().map(|(_, sig)| sig).collect();
+        let input = &mut data.inputs[index];
+        input.unlock_script = Script::multisig_unlock(&signatures, redeem_script);
+        tx.data = bincode::serialize(&data).map_err(|_| EcdsaErr::new())?;
+        Ok(())
+    }
+}
+
+// the key of an HD wallet's own address, its first receive key
+pub(crate) fn first_receive_key(account: &ExtendedPrivateKey) -> Result<PrivateKey, HdErr> {
+    let first = account.derive_path(&DerivationPath(vec![RECEIVE, 0]))?;
+    Ok(first.private_key().clone())
+}
+
+fn sign_input_with(
+    private_key: &PrivateKey,
+    tx: &mut Transaction,
+    index: usize,
+    sighash: SigHash,
+) -> Result<(), EcdsaErr> {
+    let mut data = tx.tx_data().map_err(|_| EcdsaErr::new())?;
+    let signed_data = data
+        .signature_hash(index, sighash)
+        .ok_or_else(EcdsaErr::new)?;
+    let signature = private_key.sign_recoverable(&signed_data)?;
+    data.inputs[index].unlock_script = Script::p2pkh_compact_unlock(&signature, sighash);
+    tx.data = bincode::serialize(&data).map_err(|_| EcdsaErr::new())?;
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::core::cyphers::Encoder;
-    use crate::core::transaction::Transaction;
+    use crate::core::script::{verify_script, ScriptContext, ScriptErr};
+    use crate::core::transaction::TxValidationErr;
+    use crate::core::utxo::UtxoEntry;
+
+    fn prev_out() -> OutPoint {
+        OutPoint {
+            tx_id: "prev_tx".into(),
+            index: 0,
+        }
+    }
 
     // give a better test name.
     #[test]
     fn wallet() {
-        let w = Wallet::new(vec![]).unwrap();
-        let trx_result = w.create_transaction("recv_hex".to_string(), 1.0);
+        let mut w = Wallet::new(vec![]).unwrap();
+        let trx_result = w.build_transaction(
+            vec![prev_out()],
+            w.address.clone(),
+            Amount::from_coins(1).unwrap(),
+            Amount::ZERO,
+        );
         dbg!(&trx_result);
         assert!(trx_result.is_ok());
 
-        let mut trx = trx_result.unwrap();
+        let trx = trx_result.unwrap();
         let signing_result = w.sign_data(&mut trx.data.as_slice());
         dbg!(&signing_result);
         assert!(signing_result.is_ok());
 
-        trx.signature = Some(signing_result.unwrap());
+        let signature = signing_result.unwrap();
 
         // Before:
         // trx serialize: verify trx data - valid
-        let clone_signature = trx.signature.clone();
-        let valid = w
-            .public_key
-            .verify(&clone_signature.unwrap(), &trx.data[..]);
+        let valid = w.public_key.verify(&signature, &trx.data[..]);
         assert!(valid.is_ok());
 
         // After:
@@ -82,9 +711,310 @@ mod tests {
         assert!(ser_trx_result.is_ok());
 
         let de_trx: Transaction = bincode::deserialize(&ser_trx_result.unwrap()).expect("de_trx");
-        let after_valid = w
-            .public_key
-            .verify(&de_trx.signature.unwrap(), &de_trx.data);
+        let after_valid = w.public_key.verify(&signature, &de_trx.data);
         assert!(after_valid.is_ok());
     }
+
+    #[test]
+    fn keystore() {
+        let dir = crate::core::storage::temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallet.key");
+
+        let mut w = Wallet::create(&path, "first").unwrap();
+        assert!(!w.is_locked());
+        let secret = w.private_key.as_ref().unwrap().as_hex();
+        assert!(!format!("{:?}", w).contains(&secret));
+        assert!(!format!("{:?}", w.private_key).contains(&secret));
+
+        // an existing keystore is never overwritten
+        assert!(matches!(
+            Wallet::create(&path, "other"),
+            Err(KeystoreErr::AlreadyExists)
+        ));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let signature = w.sign_data(b"data").unwrap();
+        assert!(w.public_key().verify(&signature, b"data").is_ok());
+
+        w.lock().unwrap();
+        assert!(w.is_locked());
+        assert!(w.sign_data(b"data").is_err());
+        assert!(matches!(
+            w.unlock("wrong"),
+            Err(KeystoreErr::WrongPassphrase)
+        ));
+        w.unlock("first").unwrap();
+        assert!(w.sign_data(b"data").is_ok());
+
+        // reopened locked, with the same key
+        let mut reopened = Wallet::open(&path).unwrap();
+        assert!(reopened.is_locked());
+        assert_eq!(reopened.address, w.address);
+        assert!(matches!(
+            reopened.change_passphrase("wrong", "second"),
+            Err(KeystoreErr::WrongPassphrase)
+        ));
+        reopened.change_passphrase("first", "second").unwrap();
+        assert!(reopened.is_locked());
+        assert!(reopened.unlock("first").is_err());
+        reopened.unlock("second").unwrap();
+        let signature = reopened.sign_data(b"data").unwrap();
+        assert!(w.public_key().verify(&signature, b"data").is_ok());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // an in memory wallet has nothing to unlock from
+        let mut in_memory = Wallet::new(vec![]).unwrap();
+        assert!(matches!(in_memory.lock(), Err(KeystoreErr::NotPersisted)));
+        assert!(!in_memory.is_locked());
+        assert!(Wallet::open(dir.join("missing.key")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hd_wallet() {
+        let seed = [7u8; 32];
+        let mut w = Wallet::from_seed(&seed).unwrap();
+        assert_eq!(w.address, Wallet::from_seed(&seed).unwrap().address);
+        assert_eq!(w.derive(false, 0).unwrap().address, w.address);
+
+        let receive = w.fresh_address(false).unwrap();
+        let change = w.fresh_address(true).unwrap();
+        assert_ne!(receive, w.address);
+        assert_ne!(receive, change);
+        assert_eq!(w.derive(false, 1).unwrap().address, receive);
+        assert_eq!(w.derive(true, 0).unwrap().address, change);
+        assert_ne!(w.fresh_address(false).unwrap(), receive);
+
+        // a watch-only party derives the same addresses from the account xpub
+        let xpub = ExtendedPublicKey::decode(&w.account_xpub().unwrap().encode()).unwrap();
+        let watched = |path: &[u32]| xpub.derive_path(path).unwrap().public_key().address();
+        assert_eq!(watched(&[0, 1]), receive);
+        assert_eq!(watched(&[1, 0]), change);
+
+        let plain = Wallet::new(vec![]).unwrap();
+        assert!(plain.account_xpub().is_none());
+        assert!(plain.derive(false, 0).is_none());
+    }
+
+    #[test]
+    fn mnemonic_backup() {
+        let mut w = Wallet::generate_mnemonic(12, "").unwrap();
+        let phrase = w.mnemonic().unwrap();
+        assert_eq!(phrase.split(' ').count(), 12);
+        let receive = w.fresh_address(false).unwrap();
+
+        let mut restored = Wallet::from_mnemonic(&phrase, "").unwrap();
+        assert_eq!(restored.address, w.address);
+        assert_eq!(restored.fresh_address(false).unwrap(), receive);
+        assert_eq!(restored.account_xpub(), w.account_xpub());
+        assert_eq!(restored.mnemonic(), Some(phrase.clone()));
+
+        // the phrase and the account key don't show in debug output
+        let words: Vec<&str> = phrase.split(' ').collect();
+        let debug = format!("{:?} {:?}", w, w.account);
+        assert!(!debug.contains(&words[..2].join(" ")));
+        let secret = w.private_key.as_ref().unwrap().as_hex();
+        assert!(!debug.contains(&secret));
+
+        // another passphrase is another wallet
+        let other = Wallet::from_mnemonic(&phrase, "passphrase").unwrap();
+        assert_ne!(other.address, w.address);
+
+        let mut words: Vec<&str> = phrase.split(' ').collect();
+        words[3] = "notaword";
+        assert_eq!(
+            Wallet::from_mnemonic(&words.join(" "), "").unwrap_err(),
+            MnemonicErr::UnknownWord {
+                index: 3,
+                word: "notaword".into()
+            }
+        );
+        assert!(Wallet::new(vec![]).unwrap().mnemonic().is_none());
+    }
+
+    #[test]
+    fn hd_keystore() {
+        let dir = crate::core::storage::temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hd.key");
+
+        let mut w = Wallet::generate_mnemonic(12, "").unwrap();
+        let receive = w.fresh_address(false).unwrap();
+        let change = w.fresh_address(true).unwrap();
+        let xpub = w.account_xpub();
+        let phrase = w.mnemonic().unwrap();
+        assert!(matches!(w.lock(), Err(KeystoreErr::NotPersisted)));
+        w.save(&path, "passphrase").unwrap();
+        assert!(matches!(
+            w.save(&path, "other"),
+            Err(KeystoreErr::AlreadyExists)
+        ));
+
+        // no key left in memory, only the first address is known
+        w.lock().unwrap();
+        assert!(w.is_locked());
+        assert!(w.account_xpub().is_none());
+        assert!(w.mnemonic().is_none());
+        assert_eq!(w.addresses(), vec![w.address.clone()]);
+        assert!(w.derive(false, 1).is_none());
+        assert!(matches!(
+            w.save(dir.join("copy.key"), "passphrase"),
+            Err(KeystoreErr::Locked)
+        ));
+
+        w.unlock("passphrase").unwrap();
+        assert_eq!(w.account_xpub(), xpub);
+        assert_eq!(w.mnemonic(), Some(phrase.clone()));
+        assert_eq!(w.addresses(), vec![w.address.clone(), receive, change]);
+        w.unlock("passphrase").unwrap();
+        assert_eq!(w.addresses().len(), 3);
+
+        // reopened: the same wallet once unlocked
+        let mut reopened = Wallet::open(&path).unwrap();
+        assert_eq!(reopened.address, w.address);
+        assert!(reopened.account_xpub().is_none());
+        reopened.change_passphrase("passphrase", "second").unwrap();
+        reopened.unlock("second").unwrap();
+        assert_eq!(reopened.account_xpub(), xpub);
+        assert_eq!(reopened.mnemonic(), Some(phrase));
+        assert_eq!(
+            reopened.derive(true, 0).unwrap().address,
+            w.derive(true, 0).unwrap().address
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sign_transaction() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let receiver = Wallet::new(vec![]).unwrap();
+        let one = Amount::from_coins(1).unwrap();
+        let mut trx = w
+            .build_transaction(vec![prev_out()], receiver.address.clone(), one, one)
+            .unwrap();
+        let data = trx.tx_data().unwrap();
+        assert_eq!(data.outputs.len(), 2);
+        assert!(data.outputs[0].pays_to(&receiver.address));
+        assert!(data.outputs[1].pays_to(&w.address));
+        assert_eq!(trx.verify(), Err(TxValidationErr::MissingSignature));
+
+        let unsigned_id = trx.trx_id.clone();
+        w.sign_transaction(&mut trx).unwrap();
+        assert!(trx.verify().is_ok());
+        assert_eq!(trx.trx_id, unsigned_id);
+
+        // unlocks an output of the wallet
+        let data = trx.tx_data().unwrap();
+        let lock_script = Script::p2pkh_addr(&w.address).unwrap();
+        let ctx = ScriptContext {
+            tx: &data,
+            input_index: 0,
+        };
+        let unlock_script = &data.inputs[0].unlock_script;
+        assert_eq!(verify_script(unlock_script, &lock_script, &ctx), Ok(()));
+        assert_eq!(data.signer_address(0), Some(w.address.clone()));
+        // a compact unlock: the signature alone, the key is recovered from it
+        assert_eq!(unlock_script.0.len(), 1);
+        assert_eq!(unlock_script.signer_address(), None);
+        let expanded = unlock_script.expand_compact(&ctx).unwrap();
+        assert_eq!(verify_script(&expanded, &lock_script, &ctx), Ok(()));
+        assert!(unlock_script.encode().len() < expanded.encode().len());
+
+        // the id and the signatures cover the outputs: the key recovered
+        // from a signature of other data isn't the wallet's
+        let mut data = trx.tx_data().unwrap();
+        data.outputs[0].value = Amount::from_coins(2).unwrap();
+        trx.data = bincode::serialize(&data).unwrap();
+        assert_eq!(trx.verify(), Err(TxValidationErr::InvalidTxId));
+        let ctx = ScriptContext { tx: &data, ..ctx };
+        assert_eq!(
+            verify_script(unlock_script, &lock_script, &ctx),
+            Err(ScriptErr::NotEqual)
+        );
+        assert_ne!(data.signer_address(0), Some(w.address.clone()));
+
+        assert!(matches!(
+            w.build_transaction(vec![prev_out()], "recv_hex".into(), one, one),
+            Err(TxBuilderErr::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn coin_selection_fee_and_change() {
+        let mut w = Wallet::new(vec![]).unwrap();
+        let receiver = Wallet::new(vec![]).unwrap();
+        let coin = |tx_id: &str, value: &str, owner: &Wallet| {
+            let output = TxOutput {
+                value: value.parse().unwrap(),
+                lock_script: Script::p2pkh_addr(&owner.address).unwrap(),
+            };
+            let outpoint = OutPoint {
+                tx_id: tx_id.into(),
+                index: 0,
+            };
+            (outpoint, UtxoEntry { output, height: 1 })
+        };
+        let unspent = vec![
+            coin("small", "0.25", &w),
+            coin("large", "1", &w),
+            coin("other", "5", &receiver),
+            coin("medium", "0.5", &w),
+        ];
+        let fee_rate = Amount::from_units(10);
+        let value: Amount = "1.2".parse().unwrap();
+
+        let tx = w
+            .create_transaction(unspent.clone(), receiver.address.clone(), value, fee_rate)
+            .unwrap();
+        assert!(tx.verify().is_ok());
+        let data = tx.tx_data().unwrap();
+        let spent: Vec<&str> = data
+            .inputs
+            .iter()
+            .map(|i| i.prev_out.tx_id.as_str())
+            .collect();
+        assert_eq!(spent, ["large", "medium"]);
+        assert!(data.outputs[0].pays_to(&receiver.address));
+        assert_eq!(data.outputs[0].value, value);
+        assert!(data.outputs[1].pays_to(&w.address));
+
+        let fee: Amount = "1.5"
+            .parse::<Amount>()
+            .unwrap()
+            .checked_sub(data.output_value().unwrap())
+            .unwrap();
+        assert_eq!(fee, fee_rate.checked_mul(tx.size() as u64).unwrap());
+
+        // the whole balance can't pay for its own fee
+        let all: Amount = "1.75".parse().unwrap();
+        match w.create_transaction(unspent.clone(), receiver.address.clone(), all, fee_rate) {
+            Err(CreateTxErr::InsufficientFunds { needed, available }) => {
+                assert_eq!(available, all);
+                assert!(needed > all);
+            }
+            other => panic!("expected insufficient funds, got {:?}", other),
+        }
+        assert_eq!(
+            w.create_transaction(vec![], receiver.address.clone(), value, fee_rate),
+            Err(CreateTxErr::InsufficientFunds {
+                needed: value,
+                available: Amount::ZERO
+            })
+        );
+        // without fee, exactly the balance: no change output
+        let tx = w
+            .create_transaction(unspent, receiver.address.clone(), all, Amount::ZERO)
+            .unwrap();
+        assert_eq!(tx.tx_data().unwrap().outputs.len(), 1);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::core::address::Address;
 use crate::core::block_chain::BlockChain;
 use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicU8, Ordering};
@@ -11,12 +12,13 @@ const LOCAL: &str = "0.0.0.0:4321";
 // TODO: add network to chain interactions
 
 // nodes -> network <-> chain { tx_of_addr }
+// a message with a malformed address doesn't decode
 #[derive(Clone, Serialize, Deserialize, Debug)]
 enum MsgEvent {
-    PushTrx { addr: String, tx_bytes: Vec<u8> }, // 4
-    TxsOfAddr { addr: String },                  // 1
-    IsKnownAddr { addr: String },                // 2
-    RegisterMinner { addr: String },             // 3
+    PushTrx { addr: Address, tx_bytes: Vec<u8> }, // 4
+    TxsOfAddr { addr: Address },                  // 1
+    IsKnownAddr { addr: Address },                // 2
+    RegisterMinner { addr: Address },             // 3
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -106,7 +108,7 @@ impl SocketHandler {
             MsgEvent::TxsOfAddr { addr } => {
                 let ser_txs = {
                     let shared_block_chain = self.shared_block_chain.lock().unwrap();
-                    let addr_txs = shared_block_chain.txs_of_addr(addr);
+                    let addr_txs = shared_block_chain.txs_of_addr(addr.to_string());
                     bincode::serialize(&addr_txs[..]).unwrap()
                 };
                 w.write_all(&ser_txs[..]).await;
@@ -118,14 +120,14 @@ impl SocketHandler {
     }
 }
 
-async fn network(shared_block_chain: Arc<Mutex<BlockChain>>) {
+// serves the peers connecting to `listener`: bound to LOCAL by a node, to any free port by tests
+async fn network(listener: TcpListener, shared_block_chain: Arc<Mutex<BlockChain>>) {
     let (tx_term, mut rx_term) = oneshot::channel::<u8>();
-    let listener = TcpListener::bind(LOCAL).await.unwrap();
     let (msg_tx, _) = broadcast::channel::<NetworkMsg>(16);
     let client_id = Arc::new(AtomicU8::new(0));
 
     let server_loop = async {
-        println!("server listening on localhost:8080");
+        println!("server listening on {:?}", listener.local_addr());
         loop {
             tokio::select! {
                 conn_result = listener.accept() => {
@@ -166,7 +168,11 @@ async fn network(shared_block_chain: Arc<Mutex<BlockChain>>) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::transaction::{Transaction, TxBuilder};
+    use crate::core::address::Address;
+    use crate::core::amount::Amount;
+    use crate::core::cyphers::hash160;
+    use crate::core::transaction::Transaction;
+    use crate::core::wallet::Wallet;
     use tokio::{
         io::AsyncWriteExt,
         time::{self, Duration},
@@ -177,75 +183,79 @@ mod tests {
         // Steps #
         // Chain -> cons tx -> add to chain's mempool, -> minning -> add block: DONE
         // Network -> send msg::tx_of_addr -> Chain : iterate blocks to find txs of given addr
-        let a = "A".to_string();
-        let b = "B".to_string();
-        let chain = Arc::new(Mutex::new(BlockChain::new()));
-
-        let tx1 = TxBuilder::new(a.clone(), b.clone(), 1.0)
-            .inputs(vec![])
-            .outputs(vec![])
-            .build()
+        let mut a = Wallet::new(vec![]).unwrap();
+        let mut b = Wallet::new(vec![]).unwrap();
+        let miner = Address::PubKeyHash(hash160(b"miner")).to_string();
+        let fee_rate = Amount::from_units(10);
+        let mut bc = BlockChain::new();
+
+        // a is paid by a coinbase, pays b, then b pays a back
+        bc.set_block_chain_address(&a.address).unwrap();
+        bc.minning();
+        let coinbase = bc.latest_block().unwrap().transactions[0].clone();
+        bc.set_block_chain_address(&miner).unwrap();
+
+        let tx1 = a
+            .create_transaction(
+                bc.spendable_of(&a.address),
+                b.address.clone(),
+                "0.5".parse().unwrap(),
+                fee_rate,
+            )
             .expect("unable to create tx-1");
-        let tx2 = TxBuilder::new(b.clone(), a.clone(), 0.5)
-            .inputs(vec![])
-            .outputs(vec![])
-            .build()
+        assert!(bc.add_transaction(tx1.clone()).is_ok());
+        bc.minning();
+        let tx2 = b
+            .create_transaction(
+                bc.spendable_of(&b.address),
+                a.address.clone(),
+                "0.25".parse().unwrap(),
+                fee_rate,
+            )
             .expect("unable to create tx-2");
+        assert!(bc.add_transaction(tx2.clone()).is_ok());
+        bc.minning();
+        assert!(bc.mem_pool.is_empty());
 
-        chain.lock().unwrap().add_transaction(tx1);
-        chain.lock().unwrap().add_transaction(tx2);
-        chain.lock().unwrap().minning();
+        let expected = vec![coinbase, tx1, tx2.clone()];
+        assert_eq!(bc.txs_of_addr(a.address.clone()), expected);
+        assert_eq!(bc.txs_of_addr(b.address.clone()), expected[1..]);
+        assert_eq!(bc.txs_of_addr(miner.clone()).len(), 2); // both coinbases
 
-        tokio::spawn(async move {
-            time::sleep(Duration::from_millis(500)).await;
-            let mut socket = TcpStream::connect(LOCAL).await.unwrap();
-            let (mut r, mut w) = socket.split();
-
-            let read_fu = async move {
-                println!("read_fu:parking");
-                let mut buf = [0u8; 1024];
-                let read_result = r.read(&mut buf).await;
-                if let Ok(_) = read_result {
-                    let addr_txs: Vec<Transaction> = bincode::deserialize(&buf).unwrap();
-                    println!("read_fu:addr_txs {:?}", addr_txs);
-                };
-            };
+        // a port of its own, other tests run a node too
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let node_addr = listener.local_addr().unwrap();
+        tokio::spawn(network(listener, Arc::new(Mutex::new(bc))));
 
-            let send_fu = async move {
-                // Network -> send msg::tx_of_addr -> Chain : iterate blocks to find txs of given addr
-                let msg = NetworkMsg {
-                    event: MsgEvent::TxsOfAddr { addr: a.clone() },
-                    propagation: MsgPropagation::ToChain,
-                };
-                let ser_msg_result = bincode::serialize(&msg);
-                assert!(ser_msg_result.is_ok());
-
-                time::sleep(Duration::from_millis(500)).await;
-
-                println!("send_fu:send");
-                let ser_msg = ser_msg_result.unwrap();
-                w.write_all(&ser_msg[..]).await;
-                w.flush().await;
-
-                // after flush msg: give some time for reader
-                time::sleep(Duration::from_millis(500)).await;
-            };
-
-            let a = tokio::select! {
-               _ = read_fu => {},
-               _ = send_fu => {}
-            };
-        });
-
-        network(chain).await
-
-        // assert!(false)
+        // Network -> send msg::tx_of_addr -> Chain : iterate blocks to find txs of given addr
+        let mut socket = TcpStream::connect(node_addr).await.unwrap();
+        let msg = NetworkMsg {
+            event: MsgEvent::TxsOfAddr {
+                addr: a.address.parse().unwrap(),
+            },
+            propagation: MsgPropagation::ToChain,
+        };
+        socket
+            .write_all(&bincode::serialize(&msg).unwrap())
+            .await
+            .unwrap();
+        socket.flush().await.unwrap();
+
+        // the node closes the connection once it answered
+        let mut buf = vec![];
+        time::timeout(Duration::from_secs(10), socket.read_to_end(&mut buf))
+            .await
+            .expect("no answer from the node")
+            .unwrap();
+        let addr_txs: Vec<Transaction> = bincode::deserialize(&buf).unwrap();
+        assert_eq!(addr_txs, expected);
     }
 
     #[tokio::test] // MsgEvent::PushTrx
     async fn broadcast_trx() {
         let chain = Arc::new(Mutex::new(BlockChain::new()));
-        let network_handle = tokio::spawn(async { network(chain).await });
+        let listener = TcpListener::bind(LOCAL).await.unwrap();
+        let network_handle = tokio::spawn(async { network(listener, chain).await });
 
         // - spawn some connected tcpstream(s)
         // - broadcast msg to all connected
@@ -272,7 +282,7 @@ mod tests {
             let msg = NetworkMsg {
                 propagation: MsgPropagation::Broadcast,
                 event: MsgEvent::PushTrx {
-                    addr: "sender".to_string(),
+                    addr: Address::PubKeyHash(hash160(b"sender")),
                     tx_bytes: "trx_bytes".to_string().as_bytes().to_vec(),
                 },
             };